@@ -1,28 +1,45 @@
-use std::path::Path;
+use std::{
+    collections::HashSet,
+    io::Write,
+    path::{Path, PathBuf},
+};
 
 use export::LuaError;
+use query::Query;
+use show_emails::{PickerAction, PickerFilter};
 use thiserror::Error;
 
 use crate::{
+    cli::{password_prompt, user_prompt_with_initial},
     config::AppConfig,
-    db::disk::{DBError, Database},
-    fastmail::{FastMailClient, FastMailError},
+    db::disk::{
+        Backup, DBError, Database, DatabaseContent, KeySource, DEFAULT_BACKUPS_COUNT,
+        FORMAT_VERSION, SCHEMA_VERSION,
+    },
+    fastmail::{
+        ConnectionSettings, FastMailClient, FastMailError, MaskedEmailUpdate, MethodError,
+        NewMaskedEmail, UpdateResult,
+    },
+    model::masked_email::{MaskedEmail, MaskedEmailState},
     secrets::{
-        encryption::{generate_key, generate_new_salt, EncryptionError},
+        encryption::{
+            generate_key, generate_new_salt, generate_random_key, unwrap_key, wrap_key,
+            EncryptionError, KdfParams,
+        },
         fastmail::{FastMailAccount, PasswordStorageError, SecureStorage},
         AesKeyValue, PasswordValue,
     },
 };
 
 mod export;
+pub mod list_emails;
+pub mod query;
 mod show_emails;
 
 #[derive(Error, Debug)]
 pub enum ActionError {
     #[error("Problem with Password Storage: {0}")]
     PasswordStorage(#[from] PasswordStorageError),
-    #[error("AES key is not found in the keychain")]
-    PasswordNotFound,
     #[error("Fastmail password was not setup, please run `masked-email-cli update_password` to create a new one")]
     PasswordSetup,
     #[error("Encryption failure: {0}")]
@@ -35,93 +52,758 @@ pub enum ActionError {
     DatabaseNotFound(String),
     #[error("Lua script failed: {0}")]
     ExportScript(#[from] LuaError),
+    #[error("AES key derived from the fastmail token or passphrase cannot decrypt the database")]
+    KeyMismatch,
+    #[error("Database cannot be replaced when backups are disabled, please set `backups` above 0")]
+    BackupsDisabled,
+    #[error("Email is not found in the database: {0}")]
+    EmailNotFound(String),
+    #[error("User input failed: {0}")]
+    UserInput(#[from] std::io::Error),
+    #[error("Output failed: {0}")]
+    Output(std::io::Error),
+    #[error("Refresh would remove {removed} of {total} emails from the database, use --force to store it anyway")]
+    DestructiveRefresh { removed: usize, total: usize },
 }
 
 pub type Result<T> = std::result::Result<T, ActionError>;
 
-pub fn refresh_db<PasswordStorage>(config: &AppConfig) -> Result<()>
+/// Default fraction of emails that can disappear during one refresh.
+const DEFAULT_MAX_REMOVED_FRACTION: f64 = 0.25;
+
+/// Download new and changed emails and update the database.
+///
+/// # Arguments
+///
+/// * `force` - store the result even if too many emails disappear or the token cannot decrypt the database
+/// * `confirm` - asked with the removed and total emails count when too many emails disappear
+pub fn refresh_db<F>(
+    storage: &dyn SecureStorage,
+    config: &AppConfig,
+    force: bool,
+    confirm: F,
+) -> Result<()>
 where
-    PasswordStorage: SecureStorage,
+    F: FnOnce(usize, usize) -> std::io::Result<bool>,
 {
     // load token
-    let account: Option<FastMailAccount> = PasswordStorage::load_password(&config.user_name)?;
-
-    // no problems with keychain but the password is not set up
-    let account: FastMailAccount = account.ok_or(ActionError::PasswordSetup)?;
-
-    // load all masked emails
-    let emails = FastMailClient::new(PasswordValue {
-        value: account.bearer.value.clone(),
-    })
-    .and_then(|client| client.load_emails())?;
-
-    let db: Database;
-    let key: AesKeyValue;
+    let account: FastMailAccount = load_account(storage, config)?;
 
-    if let Ok(existed) = Database::init(&config.storage) {
-        db = existed;
+    let client = make_client(config, &account)?;
 
-        // try to load AES key
-        let existed_key = PasswordStorage::load_key(&config.user_name)?;
+    let (db, key) = open_or_create_db(storage, config, &account, force)?;
+    let content = match db.load_content(&key) {
+        Ok(content) => content,
+        // re-encrypted with the new key, the previous version is kept as a backup
+        Err(e) if force => {
+            log::warn!("Database cannot be decrypted, the whole list will be loaded: {e}");
+            DatabaseContent::default()
+        }
+        Err(e) => return Err(e.into()),
+    };
+    let previous_ids: Vec<String> = content
+        .emails
+        .iter()
+        .map(|e| e.internal_id.clone())
+        .collect();
 
-        match existed_key {
-            Some(preloaded) => key = preloaded,
-            None => {
-                // if no key is stored then the key will be derived from user password and database salt
-                key = generate_key(&account.into(), &db.key_derivation_salt)?;
-                PasswordStorage::update_key(&config.user_name, &key)?
+    let (emails, state) = match content.jmap_state {
+        Some(since_state) => match client.load_changes(&since_state) {
+            Ok(changes) => {
+                // apply only changed emails
+                let replaced: HashSet<&str> = changes
+                    .destroyed
+                    .iter()
+                    .map(String::as_str)
+                    .chain(changes.changed.iter().map(|c| c.internal_id.as_str()))
+                    .collect();
+                let mut emails = content.emails;
+                emails.retain(|e| !replaced.contains(e.internal_id.as_str()));
+                emails.extend(changes.changed);
+                (emails, Some(changes.new_state))
+            }
+            Err(FastMailError::MethodFailed(MethodError::CannotCalculateChanges, _)) => {
+                log::warn!("Changes cannot be calculated, the whole list will be loaded");
+                let loaded = client.load_emails()?;
+                (loaded.emails, loaded.state)
             }
+            Err(e) => return Err(e.into()),
+        },
+        None => {
+            // load all masked emails
+            let loaded = client.load_emails()?;
+            (loaded.emails, loaded.state)
         }
-    } else {
-        log::warn!("Database does not exist or cannot be decrypted. New key will be generated");
-
-        // init new database (no files are created at this moment)
-        db = Database::new(&config.storage, generate_new_salt());
+    };
 
-        // make new AES key
-        key = generate_key(&account.into(), &db.key_derivation_salt)?;
+    // protect the history from an empty or truncated server response
+    let total = usize::try_from(db.records_count).expect("Records count is too big");
+    let removed = if previous_ids.is_empty() {
+        total.saturating_sub(emails.len())
+    } else {
+        let ids: HashSet<&str> = emails.iter().map(|e| e.internal_id.as_str()).collect();
+        previous_ids
+            .iter()
+            .filter(|id| !ids.contains(id.as_str()))
+            .count()
+    };
+    let max_removed_fraction = config
+        .max_removed_fraction
+        .unwrap_or(DEFAULT_MAX_REMOVED_FRACTION);
 
-        PasswordStorage::update_key(&config.user_name, &key)?
+    if !force && removed > 0 && removed as f64 > total as f64 * max_removed_fraction {
+        log::warn!("Refresh removes {removed} of {total} emails");
+        if !confirm(removed, total)? {
+            return Err(ActionError::DestructiveRefresh { removed, total });
+        }
     }
 
     // update the database
-    db.store(&emails, &key)?;
+    db.store(&emails, state.as_deref(), &key)?;
     Ok(())
 }
 
-pub fn export_lua<PasswordStorage>(config: &AppConfig, script: &Path) -> Result<()>
-where
-    PasswordStorage: SecureStorage,
-{
-    if let Ok(db) = Database::init(&config.storage) {
-        // try to load AES key
-        let existed_key = PasswordStorage::load_key(&config.user_name)?;
-        // todo: add key derivation here
-        let existed_key = existed_key.ok_or(ActionError::PasswordNotFound)?;
+/// Create the masked email on the server and add it to the database.
+///
+/// # Returns
+///
+/// created email and the error of the local database, the email exists on the server even if it is
+/// not stored
+pub fn create_email(
+    storage: &dyn SecureStorage,
+    config: &AppConfig,
+    new_email: &NewMaskedEmail,
+) -> Result<(MaskedEmail, Option<DBError>)> {
+    // load token
+    let account: FastMailAccount = load_account(storage, config)?;
 
-        let emails = db.load(&existed_key)?;
+    // the database must be readable before anything is created on the server
+    let (db, key) = open_or_create_db(storage, config, &account, false)?;
+    let mut content = db.load_content(&key)?;
 
-        export::export_lua(&emails, script)?;
-        Ok(())
-    } else {
-        Err(ActionError::DatabaseNotFound(config.storage.to_owned()))
+    let created = make_client(config, &account)?.create_email(new_email)?;
+
+    // the email exists on the server, so the local failure is not fatal
+    content.emails.push(created.clone());
+    let stored = db.store(&content.emails, content.jmap_state.as_deref(), &key);
+
+    Ok((created, stored.err()))
+}
+
+pub fn update_state(
+    storage: &dyn SecureStorage,
+    config: &AppConfig,
+    targets: &[String],
+    state: MaskedEmailState,
+) -> Result<UpdateResult> {
+    // load token
+    let account: FastMailAccount = load_account(storage, config)?;
+
+    let db = init_db(config)?;
+    let key = load_db_key(storage, config, &db)?;
+    let mut content = db.load_content(&key)?;
+
+    // resolve emails and ids to the fastmail ids
+    let ids = targets
+        .iter()
+        .map(|target| {
+            content
+                .emails
+                .iter()
+                .find(|e| matches_target(e, target))
+                .map(|e| e.internal_id.clone())
+                .ok_or_else(|| ActionError::EmailNotFound(target.to_owned()))
+        })
+        .collect::<Result<Vec<String>>>()?;
+
+    let result = make_client(config, &account)?.update_state(&ids, state)?;
+
+    // keep only the state confirmed by the server
+    if !result.updated.is_empty() {
+        content
+            .emails
+            .iter_mut()
+            .filter(|e| result.updated.contains(&e.internal_id))
+            .for_each(|e| e.state = state);
+        db.store(&content.emails, content.jmap_state.as_deref(), &key)?;
     }
+
+    Ok(result)
 }
 
-pub fn show_emails<PasswordStorage>(config: &AppConfig) -> Result<()>
+/// Edit description, domain and url of the email.
+///
+/// # Arguments
+///
+/// * `target` - email address or fastmail ID
+/// * `make_update` - builds changes from the current email values
+pub fn edit_email<F>(
+    storage: &dyn SecureStorage,
+    config: &AppConfig,
+    target: &str,
+    make_update: F,
+) -> Result<MaskedEmail>
 where
-    PasswordStorage: SecureStorage,
+    F: FnOnce(&MaskedEmail) -> std::io::Result<MaskedEmailUpdate>,
 {
-    if let Ok(db) = Database::init(&config.storage) {
-        // try to load AES key
-        let existed_key = PasswordStorage::load_key(&config.user_name)?;
-        // todo: add key derivation here
-        let existed_key = existed_key.ok_or(ActionError::PasswordNotFound)?;
-
-        let emails = db.load(&existed_key)?;
-        show_emails::interact(&emails);
-        Ok(())
+    // load token
+    let account: FastMailAccount = load_account(storage, config)?;
+
+    let db = init_db(config)?;
+    let key = load_db_key(storage, config, &db)?;
+    let DatabaseContent {
+        mut emails,
+        jmap_state,
+    } = db.load_content(&key)?;
+
+    let position = emails
+        .iter()
+        .position(|e| matches_target(e, target))
+        .ok_or_else(|| ActionError::EmailNotFound(target.to_owned()))?;
+    let update = make_update(&emails[position])?;
+
+    make_client(config, &account)?.update_email(&emails[position].internal_id, &update)?;
+
+    // empty values are removed on the server
+    let email = &mut emails[position];
+    if let Some(for_domain) = update.for_domain {
+        email.web_site = Some(for_domain).filter(|v| !v.is_empty());
+    }
+    if let Some(description) = update.description {
+        email.description = Some(description).filter(|v| !v.is_empty());
+    }
+    if let Some(url) = update.url {
+        email.integration_url = Some(url).filter(|v| !v.is_empty());
+    }
+    let edited = email.clone();
+
+    db.store(&emails, jmap_state.as_deref(), &key)?;
+    Ok(edited)
+}
+
+/// Rewrite the database in the latest file format. The previous version is kept as a backup.
+///
+/// # Returns
+///
+/// format version of the database before the migration or `None` if it already has the latest format
+pub fn migrate_db(storage: &dyn SecureStorage, config: &AppConfig) -> Result<Option<u8>> {
+    let db = init_db(config)?;
+    if db.format_version == FORMAT_VERSION && db.schema_version == SCHEMA_VERSION {
+        return Ok(None);
+    }
+
+    let key = load_db_key(storage, config, &db)?;
+    let content = db.load_content(&key)?;
+    db.store(&content.emails, content.jmap_state.as_deref(), &key)?;
+
+    Ok(Some(db.format_version))
+}
+
+/// Replace the fastmail token and re-encrypt the database with the key derived from the new token.
+/// The database gets a new salt, so the old token cannot derive its key anymore. Backups keep the old key.
+/// Databases with the passphrase key are not re-encrypted, only the token is replaced.
+/// The stored secrets are restored if the new database is not stored.
+///
+/// # Arguments
+///
+/// * `new_token` - fastmail token, it must be accepted by the server
+pub fn rotate_token(
+    storage: &dyn SecureStorage,
+    config: &AppConfig,
+    new_token: PasswordValue,
+) -> Result<()> {
+    // decrypt with the current key
+    let db = init_db(config)?;
+    let key = load_db_key(storage, config, &db)?;
+    let content = db.load_content(&key)?;
+
+    // check the new token before anything is changed
+    let account = FastMailAccount { bearer: new_token };
+    make_client(config, &account)?;
+
+    if db.key_source != KeySource::Token {
+        log::info!("Database key does not depend on the token");
+        storage.update_password(&config.account_name(), &account.bearer)?;
+        return Ok(());
+    }
+
+    // configured key derivation parameters replace the old ones
+    let new_db = new_db(config, config.kdf_params.unwrap_or(db.kdf_params));
+    let new_key = generate_key(
+        &account.bearer,
+        &new_db.key_derivation_salt,
+        &new_db.kdf_params,
+    )?;
+
+    // the secrets are replaced first and restored if anything fails, so the stored key always
+    // matches the database
+    let account_name = config.account_name();
+    let old_account = storage.load_password(&account_name)?;
+    let restore = |err: ActionError| {
+        if let Err(e) = storage.update_key(&account_name, &key) {
+            log::error!("Previous AES key was not restored: {e}");
+        }
+        if let Some(old) = &old_account {
+            if let Err(e) = storage.update_password(&account_name, &old.bearer) {
+                log::error!("Previous token was not restored: {e}");
+            }
+        }
+        err
+    };
+    storage.update_key(&account_name, &new_key)?;
+    storage
+        .update_password(&account_name, &account.bearer)
+        .map_err(|e| restore(e.into()))?;
+    new_db
+        .store(&content.emails, content.jmap_state.as_deref(), &new_key)
+        .map_err(|e| restore(e.into()))?;
+    Ok(())
+}
+
+/// Source of the database key that the user chooses.
+pub enum KeyMode {
+    /// key is derived from the fastmail token
+    Token,
+    /// key is derived from the passphrase
+    Passphrase(PasswordValue),
+    /// random key is wrapped with the key derived from the passphrase
+    WrappedKey(PasswordValue),
+}
+
+/// Re-encrypt the database with the key from another source. The database gets a new salt.
+/// Backups keep the old key.
+pub fn change_key_mode(
+    storage: &dyn SecureStorage,
+    config: &AppConfig,
+    mode: KeyMode,
+) -> Result<()> {
+    // decrypt with the current key
+    let db = init_db(config)?;
+    let key = load_db_key(storage, config, &db)?;
+    let content = db.load_content(&key)?;
+
+    let new_db = new_db(config, config.kdf_params.unwrap_or(db.kdf_params));
+    let salt = new_db.key_derivation_salt;
+    let (new_db, new_key) = match mode {
+        KeyMode::Token => {
+            let account: FastMailAccount = load_account(storage, config)?;
+            let new_key = generate_key(&account.bearer, &salt, &new_db.kdf_params)?;
+            (new_db.with_key_source(KeySource::Token), new_key)
+        }
+        KeyMode::Passphrase(passphrase) => {
+            let new_key = generate_key(&passphrase, &salt, &new_db.kdf_params)?;
+            (new_db.with_key_source(KeySource::Passphrase), new_key)
+        }
+        KeyMode::WrappedKey(passphrase) => {
+            let wrapping_key = generate_key(&passphrase, &salt, &new_db.kdf_params)?;
+            let new_key = generate_random_key();
+            let wrapped = wrap_key(&wrapping_key, &new_key)?;
+            (
+                new_db.with_key_source(KeySource::WrappedKey(wrapped)),
+                new_key,
+            )
+        }
+    };
+    new_db.store(&content.emails, content.jmap_state.as_deref(), &new_key)?;
+
+    storage.update_key(&config.account_name(), &new_key)?;
+    Ok(())
+}
+
+/// List all database backups, the most recent goes first.
+pub fn list_backups(config: &AppConfig) -> Result<Vec<Backup>> {
+    Ok(init_db(config)?.backups()?)
+}
+
+/// Replace the database with the backup. The backup must be decrypted with the current AES key.
+///
+/// # Arguments
+///
+/// * `index` - backup index, 1 is the most recent version
+pub fn restore_backup(storage: &dyn SecureStorage, config: &AppConfig, index: usize) -> Result<()> {
+    let db = init_db(config)?;
+    let key = load_db_key(storage, config, &db)?;
+    Ok(db.restore(index, &key)?)
+}
+
+/// Pass the emails to the lua script.
+///
+/// # Arguments
+///
+/// * `query` - only matching emails are exported, all emails without the query
+pub fn export_lua(
+    storage: &dyn SecureStorage,
+    config: &AppConfig,
+    script: &Path,
+    query: Option<&Query>,
+) -> Result<()> {
+    let emails = find_emails(storage, config, query)?;
+
+    export::export_lua(&emails, script)?;
+    Ok(())
+}
+
+/// Show the emails in the interactive fuzzy finder. The picker is shown again after every action
+/// except printing, so the emails can be copied, toggled, opened or edited one by one.
+///
+/// # Arguments
+///
+/// * `query` - only matching emails are shown, all emails without the query
+pub fn show_emails(
+    storage: &dyn SecureStorage,
+    config: &AppConfig,
+    query: Option<&Query>,
+) -> Result<()> {
+    let mut emails = find_emails(storage, config, query)?;
+    let mut search = String::new();
+    let mut status = String::new();
+    let mut filter = PickerFilter::default();
+
+    while let Some(picked) = show_emails::pick(&emails, &search, &filter, &status) {
+        search = picked.query;
+        let selected: Vec<MaskedEmail> = emails
+            .iter()
+            .filter(|e| picked.ids.contains(&e.internal_id))
+            .cloned()
+            .collect();
+
+        status = match picked.action {
+            PickerAction::NextStateFilter => {
+                filter.next_state();
+                String::new()
+            }
+            PickerAction::NextLastMessageFilter => {
+                filter.next_last_message();
+                String::new()
+            }
+            PickerAction::Print => {
+                println!();
+                for email in selected {
+                    println!("{:#?}", email);
+                }
+                return Ok(());
+            }
+            PickerAction::CopyEmail => {
+                let addresses: Vec<&str> = selected.iter().map(|e| e.email.as_str()).collect();
+                match show_emails::copy_to_clipboard(&addresses.join("\n")) {
+                    Ok(()) => format!("Copied {}", addresses.join(", ")),
+                    Err(err) => format!("Clipboard failed: {err}"),
+                }
+            }
+            PickerAction::ToggleState => toggle_states(storage, config, &selected, &mut emails),
+            PickerAction::OpenWebsite => selected
+                .iter()
+                .map(
+                    |email| match email.web_site.as_deref().and_then(show_emails::website_url) {
+                        Some(url) => match show_emails::open_url(&url) {
+                            Ok(()) => format!("Opened {url}"),
+                            Err(err) => format!("{url} is not opened: {err}"),
+                        },
+                        None => format!("{} has no http or https website", email.email),
+                    },
+                )
+                .collect::<Vec<String>>()
+                .join(", "),
+            PickerAction::EditDescription => selected
+                .iter()
+                .map(|email| {
+                    let edited = edit_email(storage, config, &email.internal_id, |current| {
+                        let prompt = format!("Description of {}", current.email);
+                        let description = user_prompt_with_initial(
+                            &prompt,
+                            current.description.as_deref().unwrap_or_default(),
+                        )?;
+                        Ok(MaskedEmailUpdate {
+                            for_domain: None,
+                            description: Some(description),
+                            url: None,
+                        })
+                    });
+                    match edited {
+                        Ok(edited) => {
+                            let message = format!("Updated {}", edited.email);
+                            if let Some(e) = emails
+                                .iter_mut()
+                                .find(|e| e.internal_id == edited.internal_id)
+                            {
+                                *e = edited;
+                            }
+                            message
+                        }
+                        Err(err) => format!("{} is not updated: {err}", email.email),
+                    }
+                })
+                .collect::<Vec<String>>()
+                .join(", "),
+        };
+    }
+    Ok(())
+}
+
+/// Enable disabled emails and disable enabled ones, the shown emails get the confirmed state.
+///
+/// # Returns
+///
+/// status message for the picker
+fn toggle_states(
+    storage: &dyn SecureStorage,
+    config: &AppConfig,
+    selected: &[MaskedEmail],
+    emails: &mut [MaskedEmail],
+) -> String {
+    let mut messages = Vec::new();
+    for state in [MaskedEmailState::Active, MaskedEmailState::Disabled] {
+        let targets: Vec<String> = selected
+            .iter()
+            .filter(|e| show_emails::toggled_state(e.state) == state)
+            .map(|e| e.internal_id.clone())
+            .collect();
+        if targets.is_empty() {
+            continue;
+        }
+
+        match update_state(storage, config, &targets, state) {
+            Ok(result) => {
+                for email in emails
+                    .iter_mut()
+                    .filter(|e| result.updated.contains(&e.internal_id))
+                {
+                    email.state = state;
+                    messages.push(format!("{}: {state}", email.email));
+                }
+                for (id, reason) in result.not_updated {
+                    messages.push(format!("{id} was not updated: {reason}"));
+                }
+            }
+            Err(err) => messages.push(format!("Operation failed: {err}")),
+        }
+    }
+    messages.join(", ")
+}
+
+/// Load the emails from the database that match the query, nothing is requested from the server.
+///
+/// # Returns
+///
+/// all emails when the query is not provided
+pub fn find_emails(
+    storage: &dyn SecureStorage,
+    config: &AppConfig,
+    query: Option<&Query>,
+) -> Result<Vec<MaskedEmail>> {
+    let db = init_db(config)?;
+    let key = load_db_key(storage, config, &db)?;
+
+    let mut emails = db.load(&key)?;
+    if let Some(query) = query {
+        emails.retain(|e| query.matches(e));
+    }
+    Ok(emails)
+}
+
+/// Print the emails from the database that match the filter, nothing is requested from the server.
+///
+/// # Arguments
+///
+/// * `options` - filter, order and output format
+/// * `out` - where the list is written
+pub fn list_emails(
+    storage: &dyn SecureStorage,
+    config: &AppConfig,
+    options: &list_emails::ListOptions,
+    out: &mut impl Write,
+) -> Result<()> {
+    let db = init_db(config)?;
+    let key = load_db_key(storage, config, &db)?;
+
+    let emails = db.load(&key)?;
+    let selected = list_emails::select(&emails, options);
+    list_emails::write_emails(&selected, options.format, out).map_err(ActionError::Output)
+}
+
+/// Print the emails found by the email address, fastmail ID or website, nothing is requested from the server.
+///
+/// # Arguments
+///
+/// * `target` - email address, fastmail ID or website, the website matches by the host without `www.`
+/// * `format` - output format
+/// * `out` - where the emails are written
+///
+/// # Returns
+///
+/// number of found emails, nothing is written when it is 0
+pub fn get_emails(
+    storage: &dyn SecureStorage,
+    config: &AppConfig,
+    target: &str,
+    format: list_emails::OutputFormat,
+    out: &mut impl Write,
+) -> Result<usize> {
+    let emails = find_emails(storage, config, None)?;
+
+    let found: Vec<&MaskedEmail> = emails
+        .iter()
+        .filter(|e| matches_target(e, target) || matches_website(e, target))
+        .collect();
+    if !found.is_empty() {
+        list_emails::write_emails(&found, format, out).map_err(ActionError::Output)?;
+    }
+    Ok(found.len())
+}
+
+/// Check the email has the fastmail ID or the email address.
+fn matches_target(email: &MaskedEmail, target: &str) -> bool {
+    email.internal_id == target || email.email.eq_ignore_ascii_case(target)
+}
+
+/// Check the email website has the same host, `https://www.example.com/login` matches `example.com`.
+fn matches_website(email: &MaskedEmail, target: &str) -> bool {
+    let normalize = |url: &str| {
+        let host = query::host(url).to_lowercase();
+        host.strip_prefix("www.").map(str::to_owned).unwrap_or(host)
+    };
+    let target = normalize(target);
+    !target.is_empty()
+        && email
+            .web_site
+            .as_deref()
+            .is_some_and(|web_site| normalize(web_site) == target)
+}
+
+/// Load the fastmail token from the password storage.
+fn load_account(storage: &dyn SecureStorage, config: &AppConfig) -> Result<FastMailAccount> {
+    let account: Option<FastMailAccount> = storage.load_password(&config.account_name())?;
+
+    // no problems with keychain but the password is not set up
+    account.ok_or(ActionError::PasswordSetup)
+}
+
+/// Open the fastmail session using the connection parameters from the config.
+fn make_client(config: &AppConfig, account: &FastMailAccount) -> Result<FastMailClient> {
+    let mut settings = ConnectionSettings {
+        accept_invalid_certs: config.accept_invalid_certs,
+        ca_certificate: config.ca_certificate.as_ref().map(PathBuf::from),
+        ..Default::default()
+    };
+    if let Some(session_url) = &config.session_url {
+        settings.session_url = session_url.to_owned();
+    }
+
+    let token = PasswordValue {
+        value: account.bearer.value.clone(),
+    };
+    Ok(FastMailClient::new(token, &settings)?)
+}
+
+/// Open the existing database with the configured backups count.
+fn init_db(config: &AppConfig) -> Result<Database> {
+    Database::init(&config.storage)
+        .map(|db| db.with_backups(config.backups.unwrap_or(DEFAULT_BACKUPS_COUNT)))
+        .map_err(|e| match e {
+            DBError::FileNotFound(_) => ActionError::DatabaseNotFound(config.storage.to_owned()),
+            e => ActionError::Database(e),
+        })
+}
+
+/// Init a new database with a new salt, no files are created at this moment.
+fn new_db(config: &AppConfig, kdf_params: KdfParams) -> Database {
+    Database::new(&config.storage, generate_new_salt())
+        .with_kdf_params(kdf_params)
+        .with_backups(config.backups.unwrap_or(DEFAULT_BACKUPS_COUNT))
+}
+
+/// Open the existing database or init a new one if that doesn't exist. Databases that cannot be read
+/// are reported and never replaced.
+/// The AES key is loaded from the password storage or derived from the fastmail token.
+///
+/// # Arguments
+///
+/// * `rekey` - the database that the token cannot decrypt gets the key from the current token,
+///   its content is replaced by the next store and the current version becomes the first backup
+fn open_or_create_db(
+    storage: &dyn SecureStorage,
+    config: &AppConfig,
+    account: &FastMailAccount,
+    rekey: bool,
+) -> Result<(Database, AesKeyValue)> {
+    let db: Database;
+    let key: AesKeyValue;
+
+    let existed = match init_db(config) {
+        Ok(existed) => Some(existed),
+        Err(ActionError::DatabaseNotFound(_)) => None,
+        Err(e) => return Err(e),
+    };
+    if let Some(existed) = existed {
+        key = match load_db_key(storage, config, &existed) {
+            Ok(key) => key,
+            Err(ActionError::KeyMismatch) if rekey && existed.key_source == KeySource::Token => {
+                // the old version must survive as a backup
+                if config.backups == Some(0) {
+                    return Err(ActionError::BackupsDisabled);
+                }
+                log::warn!("Database cannot be decrypted with the token, new key will be used");
+                // the key is stored by the next run when it decrypts the new version
+                generate_key(
+                    &account.bearer,
+                    &existed.key_derivation_salt,
+                    &existed.kdf_params,
+                )?
+            }
+            Err(e) => return Err(e),
+        };
+        db = existed;
     } else {
-        Err(ActionError::DatabaseNotFound(config.storage.to_owned()))
+        log::warn!("Database does not exist. New key will be generated");
+
+        // init new database (no files are created at this moment)
+        db = new_db(config, config.kdf_params.unwrap_or_default());
+
+        // make new AES key
+        key = generate_key(&account.bearer, &db.key_derivation_salt, &db.kdf_params)?;
+
+        storage.update_key(&config.account_name(), &key)?
+    }
+
+    Ok((db, key))
+}
+
+/// Load the AES key from the password storage.
+/// If no key is stored then the key will be derived from the fastmail token (or the passphrase) and
+/// database salt, that happens on a new machine. The derived key is stored only if it decrypts the database.
+fn load_db_key(
+    storage: &dyn SecureStorage,
+    config: &AppConfig,
+    db: &Database,
+) -> Result<AesKeyValue> {
+    match storage.load_key(&config.account_name())? {
+        Some(preloaded) => Ok(preloaded),
+        None => {
+            let key = match &db.key_source {
+                KeySource::Token => {
+                    log::info!("AES key is not found, it will be derived from the fastmail token");
+                    let account: FastMailAccount = load_account(storage, config)?;
+                    generate_key(&account.bearer, &db.key_derivation_salt, &db.kdf_params)?
+                }
+                KeySource::Passphrase => {
+                    let passphrase = password_prompt("Please enter the database passphrase")?;
+                    generate_key(&passphrase, &db.key_derivation_salt, &db.kdf_params)?
+                }
+                KeySource::WrappedKey(wrapped) => {
+                    let passphrase = password_prompt("Please enter the database passphrase")?;
+                    let wrapping_key =
+                        generate_key(&passphrase, &db.key_derivation_salt, &db.kdf_params)?;
+                    unwrap_key(&wrapping_key, wrapped).map_err(|_| ActionError::KeyMismatch)?
+                }
+            };
+
+            // the token could be changed since the database was created, the authentication
+            // tag of the encrypted block is checked even for an empty database
+            db.load_content(&key)
+                .map_err(|_| ActionError::KeyMismatch)?;
+
+            storage.update_key(&config.account_name(), &key)?;
+            Ok(key)
+        }
     }
 }
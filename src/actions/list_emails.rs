@@ -0,0 +1,413 @@
+use std::{cmp::Ordering, io::Write, str::FromStr};
+
+use chrono::{DateTime, Duration, NaiveDate, Utc};
+use regex::Regex;
+use serde::Serialize;
+
+use crate::model::masked_email::{MaskedEmail, MaskedEmailState};
+
+use super::query::Query;
+
+/// Conditions that all listed emails satisfy, empty conditions are ignored.
+#[derive(Default)]
+pub struct EmailFilter {
+    /// any of the states
+    pub states: Vec<MaskedEmailState>,
+    /// case insensitive substring of the website
+    pub domain: Option<String>,
+    pub description: Option<Regex>,
+    pub created_after: Option<DateTime<Utc>>,
+    pub created_before: Option<DateTime<Utc>>,
+    pub last_message_after: Option<DateTime<Utc>>,
+    /// emails without messages are always older
+    pub last_message_before: Option<DateTime<Utc>>,
+    pub query: Option<Query>,
+}
+
+impl EmailFilter {
+    pub fn matches(&self, email: &MaskedEmail) -> bool {
+        let domain = email.web_site.as_deref().unwrap_or_default();
+        let description = email.description.as_deref().unwrap_or_default();
+
+        (self.states.is_empty() || self.states.contains(&email.state))
+            && self
+                .domain
+                .as_ref()
+                .map_or(true, |d| domain.to_lowercase().contains(&d.to_lowercase()))
+            && self
+                .description
+                .as_ref()
+                .map_or(true, |r| r.is_match(description))
+            && self.created_after.map_or(true, |d| email.created_at >= d)
+            && self.created_before.map_or(true, |d| email.created_at < d)
+            && self
+                .last_message_after
+                .map_or(true, |d| email.last_message_at.is_some_and(|at| at >= d))
+            && self
+                .last_message_before
+                .map_or(true, |d| email.last_message_at.map_or(true, |at| at < d))
+            && self.query.as_ref().map_or(true, |q| q.matches(email))
+    }
+}
+
+/// Field the emails are sorted by.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SortKey {
+    Email,
+    Domain,
+    State,
+    Created,
+    LastMessage,
+}
+
+impl FromStr for SortKey {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "email" => Ok(SortKey::Email),
+            "domain" => Ok(SortKey::Domain),
+            "state" => Ok(SortKey::State),
+            "created" => Ok(SortKey::Created),
+            "last-message" => Ok(SortKey::LastMessage),
+            other => Err(format!("unknown sort key: {other}")),
+        }
+    }
+}
+
+impl SortKey {
+    fn compare(&self, left: &MaskedEmail, right: &MaskedEmail) -> Ordering {
+        match self {
+            SortKey::Email => left.email.cmp(&right.email),
+            SortKey::Domain => left.web_site.cmp(&right.web_site),
+            SortKey::State => left.state.to_string().cmp(&right.state.to_string()),
+            SortKey::Created => left.created_at.cmp(&right.created_at),
+            SortKey::LastMessage => left.last_message_at.cmp(&right.last_message_at),
+        }
+    }
+}
+
+/// Output format of the list.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum OutputFormat {
+    /// aligned columns for people
+    Table,
+    /// one JSON array
+    Json,
+    /// one JSON object per line
+    Ndjson,
+    Csv,
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "table" => Ok(OutputFormat::Table),
+            "json" => Ok(OutputFormat::Json),
+            "ndjson" => Ok(OutputFormat::Ndjson),
+            "csv" => Ok(OutputFormat::Csv),
+            other => Err(format!("unknown output format: {other}")),
+        }
+    }
+}
+
+/// What is listed and how.
+pub struct ListOptions {
+    pub filter: EmailFilter,
+    pub sort: Option<SortKey>,
+    pub reverse: bool,
+    pub limit: Option<usize>,
+    pub format: OutputFormat,
+}
+
+/// Email fields in the machine readable output.
+#[derive(Serialize)]
+struct EmailRecord<'a> {
+    id: &'a str,
+    email: &'a str,
+    state: String,
+    domain: Option<&'a str>,
+    description: Option<&'a str>,
+    url: Option<&'a str>,
+    created_at: DateTime<Utc>,
+    last_message_at: Option<DateTime<Utc>>,
+}
+
+impl<'a> From<&'a MaskedEmail> for EmailRecord<'a> {
+    fn from(email: &'a MaskedEmail) -> Self {
+        EmailRecord {
+            id: &email.internal_id,
+            email: &email.email,
+            state: email.state.to_string(),
+            domain: email.web_site.as_deref(),
+            description: email.description.as_deref(),
+            url: email.integration_url.as_deref(),
+            created_at: email.created_at,
+            last_message_at: email.last_message_at,
+        }
+    }
+}
+
+/// Filter, sort and limit the emails.
+pub fn select<'a>(emails: &'a [MaskedEmail], options: &ListOptions) -> Vec<&'a MaskedEmail> {
+    let mut selected: Vec<&MaskedEmail> = emails
+        .iter()
+        .filter(|e| options.filter.matches(e))
+        .collect();
+    if let Some(sort) = options.sort {
+        selected.sort_by(|l, r| sort.compare(l, r));
+    }
+    if options.reverse {
+        selected.reverse();
+    }
+    if let Some(limit) = options.limit {
+        selected.truncate(limit);
+    }
+    selected
+}
+
+/// Print the emails in the format.
+pub fn write_emails(
+    emails: &[&MaskedEmail],
+    format: OutputFormat,
+    out: &mut impl Write,
+) -> std::io::Result<()> {
+    match format {
+        OutputFormat::Table => write_table(emails, out),
+        OutputFormat::Json => {
+            let records: Vec<EmailRecord> = emails.iter().map(|e| EmailRecord::from(*e)).collect();
+            serde_json::to_writer_pretty(&mut *out, &records)?;
+            writeln!(out)
+        }
+        OutputFormat::Ndjson => {
+            for email in emails {
+                serde_json::to_writer(&mut *out, &EmailRecord::from(*email))?;
+                writeln!(out)?;
+            }
+            Ok(())
+        }
+        OutputFormat::Csv => write_csv(emails, out),
+    }
+}
+
+const TABLE_HEADER: [&str; 6] = [
+    "EMAIL",
+    "STATE",
+    "DOMAIN",
+    "DESCRIPTION",
+    "CREATED",
+    "LAST MESSAGE",
+];
+
+fn write_table(emails: &[&MaskedEmail], out: &mut impl Write) -> std::io::Result<()> {
+    let rows: Vec<[String; 6]> = emails
+        .iter()
+        .map(|e| {
+            [
+                e.email.clone(),
+                e.state.to_string(),
+                e.web_site.clone().unwrap_or_default(),
+                e.description.clone().unwrap_or_default(),
+                e.created_at.format("%Y-%m-%d").to_string(),
+                e.last_message_at
+                    .map(|at| at.format("%Y-%m-%d").to_string())
+                    .unwrap_or_else(|| "never".to_owned()),
+            ]
+        })
+        .collect();
+
+    let mut widths = TABLE_HEADER.map(|h| h.chars().count());
+    for row in &rows {
+        for (width, cell) in widths.iter_mut().zip(row) {
+            *width = (*width).max(cell.chars().count());
+        }
+    }
+
+    let header = TABLE_HEADER.map(str::to_owned);
+    for row in std::iter::once(&header).chain(&rows) {
+        let line: Vec<String> = row
+            .iter()
+            .zip(widths)
+            .map(|(cell, width)| format!("{cell:width$}"))
+            .collect();
+        writeln!(out, "{}", line.join("  ").trim_end())?;
+    }
+    Ok(())
+}
+
+const CSV_HEADER: &str = "id,email,state,domain,description,url,created_at,last_message_at";
+
+fn write_csv(emails: &[&MaskedEmail], out: &mut impl Write) -> std::io::Result<()> {
+    writeln!(out, "{CSV_HEADER}")?;
+    for email in emails {
+        let fields = [
+            email.internal_id.clone(),
+            email.email.clone(),
+            email.state.to_string(),
+            email.web_site.clone().unwrap_or_default(),
+            email.description.clone().unwrap_or_default(),
+            email.integration_url.clone().unwrap_or_default(),
+            email.created_at.to_rfc3339(),
+            email
+                .last_message_at
+                .map(|at| at.to_rfc3339())
+                .unwrap_or_default(),
+        ];
+        let line: Vec<String> = fields.iter().map(|f| csv_field(f)).collect();
+        writeln!(out, "{}", line.join(","))?;
+    }
+    Ok(())
+}
+
+/// Quote the field if that has separators or quotes (RFC 4180).
+fn csv_field(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_owned()
+    }
+}
+
+/// Parse the date from the command line: `2024-01-31`, RFC 3339 time or the age relative to now
+/// (`90d`, `12w`, `6m`, `2y`).
+pub fn parse_date(value: &str) -> Result<DateTime<Utc>, String> {
+    parse_date_at(value, Utc::now())
+}
+
+/// Parse the date, relative ages are counted from `now`.
+pub fn parse_date_at(value: &str, now: DateTime<Utc>) -> Result<DateTime<Utc>, String> {
+    if let Ok(date) = NaiveDate::parse_from_str(value, "%Y-%m-%d") {
+        return Ok(date
+            .and_hms_opt(0, 0, 0)
+            .expect("Midnight exists")
+            .and_utc());
+    }
+    if let Ok(time) = DateTime::parse_from_rfc3339(value) {
+        return Ok(time.with_timezone(&Utc));
+    }
+
+    parse_age(value)
+        .and_then(|age| now.checked_sub_signed(age))
+        .ok_or_else(|| format!("`{value}` is not a date or an age like 90d"))
+}
+
+/// Parse the age: `90d`, `12w`, `6m` (30 days) or `2y` (365 days). Ages that don't fit the duration are
+/// rejected.
+pub fn parse_age(value: &str) -> Option<Duration> {
+    let unit_days = match value.chars().last()? {
+        'd' => 1,
+        'w' => 7,
+        'm' => 30,
+        'y' => 365,
+        _ => return None,
+    };
+    let count: i64 = value[..value.len() - 1].parse().ok()?;
+    Duration::try_days(count.checked_mul(unit_days)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{TimeZone, Utc};
+    use regex::Regex;
+
+    use crate::model::masked_email::{MaskedEmail, MaskedEmailState};
+
+    use super::{
+        parse_date_at, select, write_emails, EmailFilter, ListOptions, OutputFormat, SortKey,
+    };
+
+    fn email(name: &str, state: MaskedEmailState, created_year: i32) -> MaskedEmail {
+        MaskedEmail {
+            internal_id: format!("id-{name}"),
+            email: format!("{name}@mock.example"),
+            description: Some(format!("{name}, with comma")),
+            web_site: Some(format!("https://{name}.shop")),
+            integration_url: None,
+            state,
+            created_at: Utc.with_ymd_and_hms(created_year, 1, 1, 0, 0, 0).unwrap(),
+            last_message_at: None,
+        }
+    }
+
+    fn options(filter: EmailFilter) -> ListOptions {
+        ListOptions {
+            filter,
+            sort: Some(SortKey::Created),
+            reverse: false,
+            limit: None,
+            format: OutputFormat::Csv,
+        }
+    }
+
+    #[test]
+    fn filters_sort_and_limit() {
+        let emails = vec![
+            email("third", MaskedEmailState::Active, 2023),
+            email("first", MaskedEmailState::Active, 2021),
+            email("disabled", MaskedEmailState::Disabled, 2022),
+        ];
+
+        let active = options(EmailFilter {
+            states: vec![MaskedEmailState::Active],
+            ..Default::default()
+        });
+        let selected: Vec<&str> = select(&emails, &active)
+            .iter()
+            .map(|e| e.internal_id.as_str())
+            .collect();
+        assert_eq!(selected, vec!["id-first", "id-third"]);
+
+        let recent = ListOptions {
+            reverse: true,
+            limit: Some(1),
+            ..options(EmailFilter {
+                domain: Some("SHOP".to_owned()),
+                description: Some(Regex::new("^(first|third)").unwrap()),
+                created_after: Some(Utc.with_ymd_and_hms(2021, 6, 1, 0, 0, 0).unwrap()),
+                last_message_before: Some(Utc::now()),
+                ..Default::default()
+            })
+        };
+        let selected = select(&emails, &recent);
+        assert_eq!(selected.len(), 1);
+        assert_eq!(selected[0].internal_id, "id-third");
+    }
+
+    #[test]
+    fn csv_fields_are_quoted() {
+        let emails = [email("first", MaskedEmailState::Active, 2021)];
+        let selected: Vec<&MaskedEmail> = emails.iter().collect();
+
+        let mut out = Vec::new();
+        write_emails(&selected, OutputFormat::Csv, &mut out).unwrap();
+        let csv = String::from_utf8(out).unwrap();
+        let lines: Vec<&str> = csv.lines().collect();
+        assert_eq!(
+            lines[1],
+            "id-first,first@mock.example,enabled,https://first.shop,\"first, with comma\",,2021-01-01T00:00:00+00:00,"
+        );
+    }
+
+    #[test]
+    fn dates_and_ages_are_parsed() {
+        let now = Utc.with_ymd_and_hms(2024, 4, 10, 12, 0, 0).unwrap();
+        assert_eq!(
+            parse_date_at("2024-01-31", now).unwrap(),
+            Utc.with_ymd_and_hms(2024, 1, 31, 0, 0, 0).unwrap()
+        );
+        assert_eq!(
+            parse_date_at("90d", now).unwrap(),
+            Utc.with_ymd_and_hms(2024, 1, 11, 12, 0, 0).unwrap()
+        );
+        assert!(parse_date_at("yesterday", now).is_err());
+
+        // too old dates are not a panic
+        assert!(parse_date_at("9999999999999d", now).is_err());
+        assert_eq!(
+            parse_date_at("100000000d", now).unwrap_err(),
+            "`100000000d` is not a date or an age like 90d"
+        );
+    }
+}
@@ -0,0 +1,591 @@
+//! Query expressions over the alias database, for instance
+//! `state:enabled and domain:*.shop and last_message<90d`.
+//!
+//! ```text
+//! query      := or
+//! or         := and ("or" and)*
+//! and        := unary ("and" unary)*
+//! unary      := "not" unary | "(" query ")" | condition
+//! condition  := field operator value
+//! ```
+
+use std::{fmt::Display, str::FromStr};
+
+use chrono::{DateTime, Utc};
+use regex::Regex;
+use thiserror::Error;
+
+use crate::model::masked_email::{MaskedEmail, MaskedEmailState};
+
+use super::list_emails::{parse_age, parse_date_at};
+
+const FIELDS: &str = "state, email, id, domain, description, url, created, last_message";
+
+#[derive(Debug, Error, PartialEq)]
+#[error("query error at column {column}: {message}")]
+pub struct QueryError {
+    /// 1-based position of the problem in the query
+    pub column: usize,
+    pub message: String,
+}
+
+/// Parsed query, it is a predicate over the emails.
+#[derive(Clone, Debug)]
+pub struct Query {
+    expr: Expr,
+}
+
+impl Query {
+    /// Parse the query, ages like `90d` are counted from `now`.
+    pub fn parse_at(query: &str, now: DateTime<Utc>) -> Result<Self, QueryError> {
+        let tokens = tokenize(query)?;
+        let mut parser = Parser {
+            tokens,
+            position: 0,
+            now,
+            end_column: query.chars().count() + 1,
+        };
+        let expr = parser.parse_or()?;
+        match parser.tokens.get(parser.position) {
+            None => Ok(Query { expr }),
+            Some(token) => Err(token.error(format!("unexpected `{}`", token.text))),
+        }
+    }
+
+    /// Check the email satisfies the query.
+    pub fn matches(&self, email: &MaskedEmail) -> bool {
+        self.expr.matches(email)
+    }
+}
+
+impl FromStr for Query {
+    type Err = QueryError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Query::parse_at(s, Utc::now())
+    }
+}
+
+#[derive(Clone, Debug)]
+enum Expr {
+    And(Box<Expr>, Box<Expr>),
+    Or(Box<Expr>, Box<Expr>),
+    Not(Box<Expr>),
+    Condition(Condition),
+}
+
+impl Expr {
+    fn matches(&self, email: &MaskedEmail) -> bool {
+        match self {
+            Expr::And(left, right) => left.matches(email) && right.matches(email),
+            Expr::Or(left, right) => left.matches(email) || right.matches(email),
+            Expr::Not(inner) => !inner.matches(email),
+            Expr::Condition(condition) => condition.matches(email),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum TextField {
+    Email,
+    Id,
+    Domain,
+    Description,
+    Url,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum DateField {
+    Created,
+    LastMessage,
+}
+
+#[derive(Clone, Debug)]
+enum TextMatch {
+    /// case insensitive substring, the value is in lower case
+    Contains(String),
+    /// `*` and `?` wildcards, the whole value must match
+    Glob(Regex),
+    /// case insensitive equality, the value is in lower case
+    Exact(String),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Comparison {
+    Less,
+    LessOrEqual,
+    Greater,
+    GreaterOrEqual,
+}
+
+impl Comparison {
+    /// Comparison of times for the comparison of ages: younger is later.
+    fn inverted(self) -> Self {
+        match self {
+            Comparison::Less => Comparison::Greater,
+            Comparison::LessOrEqual => Comparison::GreaterOrEqual,
+            Comparison::Greater => Comparison::Less,
+            Comparison::GreaterOrEqual => Comparison::LessOrEqual,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+enum Condition {
+    State {
+        state: MaskedEmailState,
+        negated: bool,
+    },
+    Text {
+        field: TextField,
+        matcher: TextMatch,
+        negated: bool,
+    },
+    Date {
+        field: DateField,
+        comparison: Comparison,
+        time: DateTime<Utc>,
+    },
+}
+
+impl Condition {
+    fn matches(&self, email: &MaskedEmail) -> bool {
+        match self {
+            Condition::State { state, negated } => (email.state == *state) != *negated,
+            Condition::Text {
+                field,
+                matcher,
+                negated,
+            } => {
+                let value = match field {
+                    TextField::Email => email.email.as_str(),
+                    TextField::Id => email.internal_id.as_str(),
+                    TextField::Domain => host(email.web_site.as_deref().unwrap_or_default()),
+                    TextField::Description => email.description.as_deref().unwrap_or_default(),
+                    TextField::Url => email.integration_url.as_deref().unwrap_or_default(),
+                };
+                let found = match matcher {
+                    TextMatch::Contains(part) => value.to_lowercase().contains(part),
+                    TextMatch::Glob(regex) => regex.is_match(value),
+                    TextMatch::Exact(expected) => value.to_lowercase() == *expected,
+                };
+                found != *negated
+            }
+            Condition::Date {
+                field,
+                comparison,
+                time,
+            } => {
+                // emails without messages are older than anything
+                let value = match field {
+                    DateField::Created => email.created_at,
+                    DateField::LastMessage => {
+                        email.last_message_at.unwrap_or(DateTime::<Utc>::MIN_UTC)
+                    }
+                };
+                match comparison {
+                    Comparison::Less => value < *time,
+                    Comparison::LessOrEqual => value <= *time,
+                    Comparison::Greater => value > *time,
+                    Comparison::GreaterOrEqual => value >= *time,
+                }
+            }
+        }
+    }
+}
+
+/// Host of the website: `https://www.example.shop/login` is `www.example.shop`.
+pub fn host(web_site: &str) -> &str {
+    let without_scheme = web_site
+        .split_once("://")
+        .map_or(web_site, |(_, rest)| rest);
+    without_scheme
+        .split(['/', ':', '?', '#'])
+        .next()
+        .unwrap_or_default()
+}
+
+#[derive(Debug, PartialEq)]
+enum TokenKind {
+    Open,
+    Close,
+    Word,
+    /// word with quotes, it is never a keyword
+    Quoted,
+}
+
+#[derive(Debug)]
+struct Token {
+    kind: TokenKind,
+    /// word without quotes
+    text: String,
+    column: usize,
+}
+
+impl Token {
+    fn error(&self, message: impl Display) -> QueryError {
+        QueryError {
+            column: self.column,
+            message: message.to_string(),
+        }
+    }
+
+    fn is_keyword(&self, keyword: &str) -> bool {
+        self.kind == TokenKind::Word && self.text.eq_ignore_ascii_case(keyword)
+    }
+}
+
+/// Split the query into words and parentheses, quotes keep spaces and parentheses in the word.
+fn tokenize(query: &str) -> Result<Vec<Token>, QueryError> {
+    let mut tokens = Vec::new();
+    let mut chars = query.chars().enumerate().peekable();
+
+    while let Some(&(index, c)) = chars.peek() {
+        let column = index + 1;
+        match c {
+            c if c.is_whitespace() => {
+                chars.next();
+            }
+            '(' | ')' => {
+                chars.next();
+                tokens.push(Token {
+                    kind: if c == '(' {
+                        TokenKind::Open
+                    } else {
+                        TokenKind::Close
+                    },
+                    text: c.to_string(),
+                    column,
+                });
+            }
+            _ => {
+                let mut text = String::new();
+                let mut quoted = false;
+                while let Some(&(quote_index, c)) = chars.peek() {
+                    if c.is_whitespace() || c == '(' || c == ')' {
+                        break;
+                    }
+                    chars.next();
+                    if c != '"' {
+                        text.push(c);
+                        continue;
+                    }
+
+                    quoted = true;
+                    loop {
+                        match chars.next() {
+                            Some((_, '"')) => break,
+                            Some((_, c)) => text.push(c),
+                            None => {
+                                return Err(QueryError {
+                                    column: quote_index + 1,
+                                    message: "quote is not closed".to_owned(),
+                                })
+                            }
+                        }
+                    }
+                }
+                tokens.push(Token {
+                    kind: if quoted {
+                        TokenKind::Quoted
+                    } else {
+                        TokenKind::Word
+                    },
+                    text,
+                    column,
+                });
+            }
+        }
+    }
+    Ok(tokens)
+}
+
+struct Parser {
+    tokens: Vec<Token>,
+    position: usize,
+    now: DateTime<Utc>,
+    /// column after the last symbol, it is reported when the query ends too early
+    end_column: usize,
+}
+
+impl Parser {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position)
+    }
+
+    fn next(&mut self) -> Result<&Token, QueryError> {
+        let token = self.tokens.get(self.position).ok_or_else(|| QueryError {
+            column: self.end_column,
+            message: "query ends too early, a condition like `state:enabled` is expected"
+                .to_owned(),
+        })?;
+        self.position += 1;
+        Ok(token)
+    }
+
+    fn parse_or(&mut self) -> Result<Expr, QueryError> {
+        let mut expr = self.parse_and()?;
+        while self.peek().is_some_and(|t| t.is_keyword("or")) {
+            self.position += 1;
+            expr = Expr::Or(Box::new(expr), Box::new(self.parse_and()?));
+        }
+        Ok(expr)
+    }
+
+    fn parse_and(&mut self) -> Result<Expr, QueryError> {
+        let mut expr = self.parse_unary()?;
+        while self.peek().is_some_and(|t| t.is_keyword("and")) {
+            self.position += 1;
+            expr = Expr::And(Box::new(expr), Box::new(self.parse_unary()?));
+        }
+        Ok(expr)
+    }
+
+    fn parse_unary(&mut self) -> Result<Expr, QueryError> {
+        let now = self.now;
+        let token = self.next()?;
+        if token.is_keyword("not") {
+            return Ok(Expr::Not(Box::new(self.parse_unary()?)));
+        }
+        match token.kind {
+            TokenKind::Open => {
+                let column = token.column;
+                let expr = self.parse_or()?;
+                match self.next() {
+                    Ok(token) if token.kind == TokenKind::Close => Ok(expr),
+                    Ok(token) => {
+                        Err(token.error(format!("`)` is expected, found `{}`", token.text)))
+                    }
+                    Err(_) => Err(QueryError {
+                        column,
+                        message: "parenthesis is not closed".to_owned(),
+                    }),
+                }
+            }
+            TokenKind::Close => Err(token.error("condition is expected before `)`")),
+            _ if token.is_keyword("and") || token.is_keyword("or") => {
+                Err(token.error(format!("condition is expected before `{}`", token.text)))
+            }
+            _ => parse_condition(token, now).map(Expr::Condition),
+        }
+    }
+}
+
+/// Parse `field<operator>value`.
+fn parse_condition(token: &Token, now: DateTime<Utc>) -> Result<Condition, QueryError> {
+    let text = &token.text;
+    let Some(split) = text.find([':', '=', '!', '<', '>']) else {
+        return Err(token.error(format!(
+            "`{text}` is not a condition, use `field:value` with one of the fields: {FIELDS}"
+        )));
+    };
+    let (field, rest) = text.split_at(split);
+    let operator = ["!=", "<=", ">=", ":", "=", "<", ">"]
+        .into_iter()
+        .find(|op| rest.starts_with(op))
+        .ok_or_else(|| token.error(format!("unknown operator in `{text}`")))?;
+    let value = &rest[operator.len()..];
+    if value.is_empty() {
+        return Err(token.error(format!("value is missing after `{field}{operator}`")));
+    }
+
+    let equality = |negated_allowed: bool| match operator {
+        ":" | "=" => Ok(false),
+        "!=" if negated_allowed => Ok(true),
+        _ => Err(token.error(format!(
+            "`{field}` is compared with `:`, `=` or `!=`, not `{operator}`"
+        ))),
+    };
+
+    match field.to_lowercase().as_str() {
+        "state" => {
+            let negated = equality(true)?;
+            let state = value
+                .to_lowercase()
+                .parse::<MaskedEmailState>()
+                .map_err(|_| {
+                    token.error(format!(
+                        "unknown state `{value}`, use pending, enabled, disabled or deleted"
+                    ))
+                })?;
+            Ok(Condition::State { state, negated })
+        }
+        name @ ("email" | "id" | "domain" | "description" | "url") => {
+            let field = match name {
+                "email" => TextField::Email,
+                "id" => TextField::Id,
+                "domain" => TextField::Domain,
+                "description" => TextField::Description,
+                _ => TextField::Url,
+            };
+            let negated = equality(true)?;
+            let matcher = if operator == ":" {
+                text_pattern(value)
+            } else {
+                TextMatch::Exact(value.to_lowercase())
+            };
+            Ok(Condition::Text {
+                field,
+                matcher,
+                negated,
+            })
+        }
+        name @ ("created" | "last_message") => {
+            let field = if name == "created" {
+                DateField::Created
+            } else {
+                DateField::LastMessage
+            };
+            let comparison = match operator {
+                "<" => Comparison::Less,
+                "<=" => Comparison::LessOrEqual,
+                ">" => Comparison::Greater,
+                ">=" => Comparison::GreaterOrEqual,
+                _ => {
+                    return Err(token.error(format!(
+                        "`{field_name}` is compared with <, <=, > or >=, not `{operator}`",
+                        field_name = name
+                    )))
+                }
+            };
+            // `last_message<90d` means less than 90 days ago, so ages are compared the other way
+            let (comparison, time) = match parse_age(value) {
+                Some(age) => (
+                    comparison.inverted(),
+                    now.checked_sub_signed(age).ok_or_else(|| {
+                        token.error(format!("`{value}` is not a date or an age like 90d"))
+                    })?,
+                ),
+                None => (
+                    comparison,
+                    parse_date_at(value, now).map_err(|e| token.error(e))?,
+                ),
+            };
+            Ok(Condition::Date {
+                field,
+                comparison,
+                time,
+            })
+        }
+        _ => Err(token.error(format!("unknown field `{field}`, use one of: {FIELDS}"))),
+    }
+}
+
+/// Value with `*` or `?` is a glob for the whole text, other values are substrings.
+fn text_pattern(value: &str) -> TextMatch {
+    if !value.contains(['*', '?']) {
+        return TextMatch::Contains(value.to_lowercase());
+    }
+
+    let mut pattern = String::from("(?i)^");
+    for c in value.chars() {
+        match c {
+            '*' => pattern.push_str(".*"),
+            '?' => pattern.push('.'),
+            c => pattern.push_str(&regex::escape(&c.to_string())),
+        }
+    }
+    pattern.push('$');
+    TextMatch::Glob(Regex::new(&pattern).expect("Escaped glob is a valid regex"))
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{DateTime, Duration, TimeZone, Utc};
+
+    use crate::model::masked_email::{MaskedEmail, MaskedEmailState};
+
+    use super::Query;
+
+    fn now() -> DateTime<Utc> {
+        Utc.with_ymd_and_hms(2024, 4, 10, 12, 0, 0).unwrap()
+    }
+
+    fn email(name: &str, web_site: &str, last_message_days: Option<i64>) -> MaskedEmail {
+        MaskedEmail {
+            internal_id: format!("id-{name}"),
+            email: format!("{name}@mock.example"),
+            description: Some(format!("{name} account")),
+            web_site: Some(web_site.to_owned()),
+            integration_url: None,
+            state: MaskedEmailState::Active,
+            created_at: Utc.with_ymd_and_hms(2022, 1, 1, 0, 0, 0).unwrap(),
+            last_message_at: last_message_days.map(|days| now() - Duration::days(days)),
+        }
+    }
+
+    fn matching(query: &str, emails: &[MaskedEmail]) -> Vec<String> {
+        let query = Query::parse_at(query, now()).expect("Query is not parsed");
+        emails
+            .iter()
+            .filter(|e| query.matches(e))
+            .map(|e| e.internal_id.clone())
+            .collect()
+    }
+
+    #[test]
+    fn conditions_are_combined() {
+        let mut disabled = email("old", "https://old.shop/login", Some(400));
+        disabled.state = MaskedEmailState::Disabled;
+        let emails = vec![
+            email("fresh", "https://www.fresh.shop", Some(10)),
+            email("silent", "https://silent.shop", None),
+            email("news", "https://news.example.com", Some(5)),
+            disabled,
+        ];
+
+        assert_eq!(
+            matching(
+                "state:enabled and domain:*.shop and last_message<90d",
+                &emails
+            ),
+            vec!["id-fresh"]
+        );
+        assert_eq!(
+            matching("last_message>1y or not state:enabled", &emails),
+            vec!["id-silent", "id-old"]
+        );
+        assert_eq!(
+            matching("(domain:shop or email:NEWS) and state!=disabled", &emails),
+            vec!["id-fresh", "id-silent", "id-news"]
+        );
+        assert_eq!(
+            matching(
+                "description:\"old account\" and created<2023-01-01",
+                &emails
+            ),
+            vec!["id-old"]
+        );
+        assert_eq!(matching("domain=silent.shop", &emails), vec!["id-silent"]);
+    }
+
+    #[test]
+    fn errors_point_to_the_problem() {
+        let error = |query: &str| Query::parse_at(query, now()).unwrap_err();
+
+        let unknown = error("state:enabled and color:red");
+        assert_eq!(unknown.column, 19);
+        assert!(unknown.message.contains("unknown field `color`"));
+
+        assert!(error("state:active").message.contains("unknown state"));
+        assert!(error("created:2024-01-01")
+            .message
+            .contains("<, <=, > or >="));
+        assert!(error("last_message<soon")
+            .message
+            .contains("not a date or an age"));
+        let too_old = error("state:enabled and last_message<100000000d");
+        assert_eq!(too_old.column, 19);
+        assert!(too_old.message.contains("not a date or an age"));
+        assert!("last_message<100000000d".parse::<Query>().is_err());
+        assert!("created>9999999999999d".parse::<Query>().is_err());
+        assert_eq!(error("state:enabled and").column, 18);
+        assert_eq!(error("(state:enabled").column, 1);
+        assert_eq!(error("state:enabled)").column, 14);
+        assert_eq!(error("description:\"open").column, 13);
+        assert_eq!(
+            error("enabled").to_string(),
+            "query error at column 1: `enabled` is not a condition, use `field:value` with one of the fields: state, email, id, domain, description, url, created, last_message"
+        );
+    }
+}
@@ -1,71 +1,539 @@
-use std::{borrow::Cow, sync::Arc};
+use std::{
+    borrow::Cow,
+    fmt::Display,
+    io::{self, Write},
+    process::{Command, Stdio},
+    sync::Arc,
+};
 
+use chrono::{DateTime, Duration, Utc};
 use skim::{
-    prelude::{unbounded, SkimOptionsBuilder},
-    ItemPreview, PreviewContext, Skim, SkimItem, SkimItemReceiver, SkimItemSender,
+    prelude::{unbounded, Event, SkimOptionsBuilder},
+    AnsiString, DisplayContext, ItemPreview, PreviewContext, Skim, SkimItem, SkimItemReceiver,
+    SkimItemSender,
 };
+use tuikit::attr::{Attr, Color};
+
+use crate::model::masked_email::{MaskedEmail, MaskedEmailState};
+
+const KEY_COPY: &str = "ctrl-y";
+const KEY_TOGGLE_STATE: &str = "ctrl-t";
+const KEY_OPEN_WEBSITE: &str = "ctrl-o";
+const KEY_EDIT_DESCRIPTION: &str = "ctrl-e";
+const KEY_STATE_FILTER: &str = "ctrl-s";
+const KEY_LAST_MESSAGE_FILTER: &str = "ctrl-x";
+
+const KEYS_HELP: &str =
+    "enter: print, ctrl-y: copy email, ctrl-t: enable/disable, ctrl-o: open website, ctrl-e: edit description\nctrl-s: state filter, ctrl-x: last message filter";
+
+/// Width of the state column, the longest state is `disabled`.
+const STATE_WIDTH: usize = 8;
+
+/// What the user wants to do with the selected emails.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum PickerAction {
+    /// print the emails and exit
+    Print,
+    CopyEmail,
+    ToggleState,
+    OpenWebsite,
+    EditDescription,
+    /// show the next state in [`PickerFilter`]
+    NextStateFilter,
+    /// show the next last message age in [`PickerFilter`]
+    NextLastMessageFilter,
+}
+
+/// Emails selected in the picker and the key that closed it.
+pub struct Picked {
+    pub action: PickerAction,
+    /// fastmail IDs of the selected emails
+    pub ids: Vec<String>,
+    /// search text, it is restored when the picker is shown again
+    pub query: String,
+}
+
+/// Age of the last message that is shown in the picker.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum LastMessageFilter {
+    #[default]
+    Any,
+    /// a message in the last 30 days
+    Recent,
+    /// no messages for 90 days
+    Quiet,
+    /// no messages for a year
+    Abandoned,
+    /// no messages at all
+    Never,
+}
+
+impl LastMessageFilter {
+    fn matches(self, last_message_at: Option<DateTime<Utc>>, now: DateTime<Utc>) -> bool {
+        let older_than =
+            |days: i64| last_message_at.map_or(true, |at| at < now - Duration::days(days));
+        match self {
+            LastMessageFilter::Any => true,
+            LastMessageFilter::Recent => !older_than(30),
+            LastMessageFilter::Quiet => older_than(90),
+            LastMessageFilter::Abandoned => older_than(365),
+            LastMessageFilter::Never => last_message_at.is_none(),
+        }
+    }
+
+    fn next(self) -> Self {
+        match self {
+            LastMessageFilter::Any => LastMessageFilter::Recent,
+            LastMessageFilter::Recent => LastMessageFilter::Quiet,
+            LastMessageFilter::Quiet => LastMessageFilter::Abandoned,
+            LastMessageFilter::Abandoned => LastMessageFilter::Never,
+            LastMessageFilter::Never => LastMessageFilter::Any,
+        }
+    }
+}
+
+impl Display for LastMessageFilter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            LastMessageFilter::Any => write!(f, "any"),
+            LastMessageFilter::Recent => write!(f, "in the last 30 days"),
+            LastMessageFilter::Quiet => write!(f, "none for 90 days"),
+            LastMessageFilter::Abandoned => write!(f, "none for a year"),
+            LastMessageFilter::Never => write!(f, "never"),
+        }
+    }
+}
+
+/// Filters that are switched inside the picker.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub struct PickerFilter {
+    /// all states if it is not set
+    pub state: Option<MaskedEmailState>,
+    pub last_message: LastMessageFilter,
+}
+
+impl PickerFilter {
+    pub fn matches(&self, email: &MaskedEmail, now: DateTime<Utc>) -> bool {
+        self.state.map_or(true, |state| email.state == state)
+            && self.last_message.matches(email.last_message_at, now)
+    }
+
+    /// Switch the state: all, enabled, disabled, pending, deleted and all again.
+    pub fn next_state(&mut self) {
+        self.state = match self.state {
+            None => Some(MaskedEmailState::Active),
+            Some(MaskedEmailState::Active) => Some(MaskedEmailState::Disabled),
+            Some(MaskedEmailState::Disabled) => Some(MaskedEmailState::TemporalPending),
+            Some(MaskedEmailState::TemporalPending) => Some(MaskedEmailState::MarkedForDeletion),
+            Some(MaskedEmailState::MarkedForDeletion) => None,
+        };
+    }
+
+    pub fn next_last_message(&mut self) {
+        self.last_message = self.last_message.next();
+    }
+}
 
-use crate::model::masked_email::MaskedEmail;
+impl Display for PickerFilter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.state {
+            Some(state) => write!(f, "state: {state}")?,
+            None => write!(f, "state: all")?,
+        }
+        write!(f, ", last message: {}", self.last_message)
+    }
+}
 
 struct WrappedMaskedEmail {
-    id: String,
-    email: String,
-    domain: String,
-    description: String,
+    email: MaskedEmail,
 }
 
 impl SkimItem for WrappedMaskedEmail {
     fn text(&self) -> skim::prelude::Cow<str> {
         Cow::Owned(format!(
-            "[{}] \"{}\" | \"{}\"",
-            self.email, self.domain, self.description
+            "{:<STATE_WIDTH$} [{}] \"{}\" | \"{}\"",
+            self.email.state.to_string(),
+            self.email.email,
+            self.email.web_site.as_deref().unwrap_or_default(),
+            self.email.description.as_deref().unwrap_or_default()
         ))
     }
 
+    fn display<'a>(&'a self, context: DisplayContext<'a>) -> AnsiString<'a> {
+        let mut line = AnsiString::from(context);
+        let state_attr = Attr {
+            fg: Color::AnsiValue(state_color(self.email.state)),
+            ..Attr::default()
+        };
+        line.override_attrs(vec![(state_attr, (0, STATE_WIDTH as u32))]);
+        line
+    }
+
     fn preview(&self, _context: PreviewContext) -> ItemPreview {
-        ItemPreview::Text(format!(
-            "Email: {}\nDomain: {}\nDescription: {}",
-            self.email, self.domain, self.description
-        ))
+        ItemPreview::AnsiText(describe(&self.email, Utc::now()))
     }
 
     fn output(&self) -> Cow<str> {
-        Cow::Borrowed(self.id.as_str())
+        Cow::Borrowed(self.email.internal_id.as_str())
+    }
+}
+
+/// ANSI color number of the state: enabled is green, disabled is yellow, deleted is red.
+fn state_color(state: MaskedEmailState) -> u8 {
+    match state {
+        MaskedEmailState::Active => 2,
+        MaskedEmailState::Disabled => 3,
+        MaskedEmailState::MarkedForDeletion => 1,
+        MaskedEmailState::TemporalPending => 6,
     }
 }
 
-pub fn interact(emails: &[MaskedEmail]) {
+/// All fields of the email for the preview, the state is colored with ANSI codes.
+fn describe(email: &MaskedEmail, now: DateTime<Utc>) -> String {
+    let time = |at: DateTime<Utc>| {
+        format!(
+            "{} ({})",
+            at.format("%Y-%m-%d %H:%M UTC"),
+            relative_age(at, now)
+        )
+    };
+    let optional = |value: &Option<String>| value.clone().unwrap_or_else(|| "-".to_owned());
+
+    format!(
+        "Email:        {}\nState:        \x1b[3{}m{}\x1b[0m\nWebsite:      {}\nDescription:  {}\nIntegration:  {}\nID:           {}\nCreated:      {}\nLast message: {}",
+        email.email,
+        state_color(email.state),
+        email.state,
+        optional(&email.web_site),
+        optional(&email.description),
+        optional(&email.integration_url),
+        email.internal_id,
+        time(email.created_at),
+        email.last_message_at.map_or_else(|| "never".to_owned(), time),
+    )
+}
+
+/// Age of the time in the largest unit, like `14 months ago`.
+fn relative_age(time: DateTime<Utc>, now: DateTime<Utc>) -> String {
+    let age = now - time;
+    let (count, unit) = if age.num_days() >= 365 {
+        (age.num_days() / 365, "year")
+    } else if age.num_days() >= 30 {
+        (age.num_days() / 30, "month")
+    } else if age.num_days() >= 1 {
+        (age.num_days(), "day")
+    } else if age.num_hours() >= 1 {
+        (age.num_hours(), "hour")
+    } else if age.num_minutes() >= 1 {
+        (age.num_minutes(), "minute")
+    } else {
+        return "just now".to_owned();
+    };
+
+    if count == 1 {
+        format!("1 {unit} ago")
+    } else {
+        format!("{count} {unit}s ago")
+    }
+}
+
+/// Show the fuzzy finder with the emails that match the filter.
+///
+/// # Arguments
+///
+/// * `query` - initial search text
+/// * `filter` - state and last message filters, they are shown above the list
+/// * `status` - result of the previous action, it is shown above the list
+///
+/// # Returns
+///
+/// nothing if the picker is closed without a selection
+pub fn pick(
+    emails: &[MaskedEmail],
+    query: &str,
+    filter: &PickerFilter,
+    status: &str,
+) -> Option<Picked> {
+    let mut header = format!("{filter}\n{KEYS_HELP}");
+    if !status.is_empty() {
+        header = format!("{status}\n{header}");
+    }
     let options = SkimOptionsBuilder::default()
         .height(Some("50%"))
         .multi(true)
         .preview(Some(""))
+        .query(Some(query))
+        .header(Some(&header))
+        .expect(Some(
+            [
+                KEY_COPY,
+                KEY_TOGGLE_STATE,
+                KEY_OPEN_WEBSITE,
+                KEY_EDIT_DESCRIPTION,
+                KEY_STATE_FILTER,
+                KEY_LAST_MESSAGE_FILTER,
+            ]
+            .join(","),
+        ))
         .build()
         .unwrap();
 
+    let now = Utc::now();
     let (tx, rx): (SkimItemSender, SkimItemReceiver) = unbounded();
-    for email in emails {
+    for email in emails.iter().filter(|e| filter.matches(e, now)) {
         tx.send(Arc::new(WrappedMaskedEmail {
-            id: email.internal_id.clone(),
-            email: email.email.clone(),
-            domain: email.web_site.as_deref().unwrap_or_default().to_owned(),
-            description: email.description.as_deref().unwrap_or_default().to_owned(),
+            email: email.clone(),
         }))
         .unwrap();
     }
     drop(tx);
 
     // `run_with` would read and show items from the stream
-    let selected_items = Skim::run_with(&options, Some(rx))
-        .map(|out| out.selected_items)
-        .unwrap_or_default();
+    let output = Skim::run_with(&options, Some(rx)).filter(|out| !out.is_abort)?;
+    let action = match &output.final_event {
+        Event::EvActAccept(Some(key)) => match key.as_str() {
+            KEY_COPY => PickerAction::CopyEmail,
+            KEY_TOGGLE_STATE => PickerAction::ToggleState,
+            KEY_OPEN_WEBSITE => PickerAction::OpenWebsite,
+            KEY_EDIT_DESCRIPTION => PickerAction::EditDescription,
+            KEY_STATE_FILTER => PickerAction::NextStateFilter,
+            KEY_LAST_MESSAGE_FILTER => PickerAction::NextLastMessageFilter,
+            _ => PickerAction::Print,
+        },
+        _ => PickerAction::Print,
+    };
+    // filters are switched even if nothing is shown
+    let filter_changed = matches!(
+        action,
+        PickerAction::NextStateFilter | PickerAction::NextLastMessageFilter
+    );
+    if output.selected_items.is_empty() && !filter_changed {
+        return None;
+    }
+
+    Some(Picked {
+        action,
+        ids: output
+            .selected_items
+            .iter()
+            .map(|item| item.output().into_owned())
+            .collect(),
+        query: output.query,
+    })
+}
+
+/// Enabled emails are disabled, all others are enabled.
+pub fn toggled_state(state: MaskedEmailState) -> MaskedEmailState {
+    match state {
+        MaskedEmailState::Active => MaskedEmailState::Disabled,
+        _ => MaskedEmailState::Active,
+    }
+}
+
+/// Url that can be opened in the browser, `example.com` becomes `https://example.com`.
+/// The website comes from the server, so only http and https urls without spaces are accepted.
+pub fn website_url(web_site: &str) -> Option<String> {
+    let web_site = web_site.trim();
+    if web_site.is_empty()
+        || web_site
+            .chars()
+            .any(|c| c.is_whitespace() || c.is_control())
+    {
+        return None;
+    }
+    match web_site.split_once("://") {
+        Some((scheme, _))
+            if scheme.eq_ignore_ascii_case("http") || scheme.eq_ignore_ascii_case("https") =>
+        {
+            Some(web_site.to_owned())
+        }
+        Some(_) => None,
+        None => Some(format!("https://{web_site}")),
+    }
+}
 
-    println!();
+/// Put the text into the system clipboard with the first available command.
+pub fn copy_to_clipboard(text: &str) -> io::Result<()> {
+    let commands: &[&[&str]] = if cfg!(target_os = "macos") {
+        &[&["pbcopy"]]
+    } else if cfg!(windows) {
+        &[&["clip"]]
+    } else {
+        &[
+            &["wl-copy"],
+            &["xclip", "-selection", "clipboard"],
+            &["xsel", "--clipboard", "--input"],
+        ]
+    };
 
-    for item in selected_items.iter() {
-        let item_id = item.output();
+    for command in commands {
+        let child = Command::new(command[0])
+            .args(&command[1..])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn();
+        let mut child = match child {
+            Ok(child) => child,
+            // try the next clipboard tool
+            Err(err) if err.kind() == io::ErrorKind::NotFound => continue,
+            Err(err) => return Err(err),
+        };
+        child
+            .stdin
+            .take()
+            .expect("Stdin is piped")
+            .write_all(text.as_bytes())?;
+        let status = child.wait()?;
+        return if status.success() {
+            Ok(())
+        } else {
+            Err(io::Error::other(format!("{} failed: {status}", command[0])))
+        };
+    }
+    Err(io::Error::new(
+        io::ErrorKind::NotFound,
+        "clipboard command is not found, please install wl-copy, xclip or xsel",
+    ))
+}
+
+/// Open the http or https url in the default browser. The url is passed as one argument, no shell
+/// parses it.
+pub fn open_url(url: &str) -> io::Result<()> {
+    if website_url(url).as_deref() != Some(url) {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("{url} is not an http or https url"),
+        ));
+    }
+    let mut command = if cfg!(target_os = "macos") {
+        Command::new("open")
+    } else if cfg!(windows) {
+        // ShellExecute opens the url, `cmd /C start` would run `&` and `|` of the url
+        Command::new("explorer")
+    } else {
+        Command::new("xdg-open")
+    };
+    let status = command
+        .arg(url)
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .status()?;
+    // explorer exits with 1 even if the url is opened
+    if status.success() || cfg!(windows) {
+        Ok(())
+    } else {
+        Err(io::Error::other(format!("browser is not opened: {status}")))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use chrono::{Duration, TimeZone, Utc};
+
+    use crate::model::masked_email::{MaskedEmail, MaskedEmailState};
+
+    use super::{
+        describe, open_url, relative_age, toggled_state, website_url, LastMessageFilter,
+        PickerFilter,
+    };
+
+    #[test]
+    fn toggle_enables_everything_but_enabled() {
+        assert_eq!(
+            toggled_state(MaskedEmailState::Active),
+            MaskedEmailState::Disabled
+        );
+        assert_eq!(
+            toggled_state(MaskedEmailState::Disabled),
+            MaskedEmailState::Active
+        );
+        assert_eq!(
+            toggled_state(MaskedEmailState::TemporalPending),
+            MaskedEmailState::Active
+        );
+    }
+
+    #[test]
+    fn website_gets_the_scheme() {
+        assert_eq!(
+            website_url("example.com").as_deref(),
+            Some("https://example.com")
+        );
+        assert_eq!(
+            website_url("http://example.com/login").as_deref(),
+            Some("http://example.com/login")
+        );
+        assert_eq!(website_url(" "), None);
+
+        // other schemes and commands are not opened
+        assert_eq!(website_url("file:///etc/passwd"), None);
+        assert_eq!(website_url("custom-app://run?x=1"), None);
+        assert_eq!(website_url("example.com & calc.exe"), None);
+        assert_eq!(
+            website_url("HTTPS://example.com?a=1&b=2").as_deref(),
+            Some("HTTPS://example.com?a=1&b=2")
+        );
+        assert!(open_url("file:///etc/passwd").is_err());
+    }
+
+    #[test]
+    fn ages_use_the_largest_unit() {
+        let now = Utc.with_ymd_and_hms(2024, 4, 10, 12, 0, 0).unwrap();
 
-        if let Some(email) = emails.iter().find(|e| e.internal_id == item_id) {
-            println!("{:#?}", email);
+        assert_eq!(relative_age(now - Duration::seconds(20), now), "just now");
+        assert_eq!(
+            relative_age(now - Duration::minutes(1), now),
+            "1 minute ago"
+        );
+        assert_eq!(relative_age(now - Duration::hours(5), now), "5 hours ago");
+        assert_eq!(relative_age(now - Duration::days(45), now), "1 month ago");
+        assert_eq!(relative_age(now - Duration::days(430), now), "1 year ago");
+        assert_eq!(relative_age(now - Duration::days(800), now), "2 years ago");
+    }
+
+    #[test]
+    fn filters_are_switched_in_a_cycle() {
+        let now = Utc.with_ymd_and_hms(2024, 4, 10, 12, 0, 0).unwrap();
+        let email = MaskedEmail {
+            internal_id: "masked-1".to_owned(),
+            email: "first@mock.example".to_owned(),
+            description: None,
+            web_site: Some("https://shop.example".to_owned()),
+            integration_url: None,
+            state: MaskedEmailState::Disabled,
+            created_at: now - Duration::days(800),
+            last_message_at: Some(now - Duration::days(430)),
+        };
+
+        let mut filter = PickerFilter::default();
+        assert!(filter.matches(&email, now));
+        filter.next_state();
+        assert_eq!(filter.state, Some(MaskedEmailState::Active));
+        assert!(!filter.matches(&email, now));
+        filter.next_state();
+        assert!(filter.matches(&email, now));
+
+        filter.next_last_message();
+        assert_eq!(filter.last_message, LastMessageFilter::Recent);
+        assert!(!filter.matches(&email, now));
+        filter.next_last_message();
+        filter.next_last_message();
+        assert_eq!(filter.last_message, LastMessageFilter::Abandoned);
+        assert!(filter.matches(&email, now));
+        filter.next_last_message();
+        assert!(!filter.matches(&email, now));
+        for _ in 0..3 {
+            filter.next_state();
         }
+        filter.next_last_message();
+        assert_eq!(filter, PickerFilter::default());
+
+        let preview = describe(&email, now);
+        assert!(preview.contains("Website:      https://shop.example"));
+        assert!(preview.contains("Integration:  -"));
+        assert!(preview.contains("ID:           masked-1"));
+        assert!(preview.contains("Last message: 2023-02-05 12:00 UTC (1 year ago)"));
+        assert!(preview.contains("\x1b[33mdisabled"));
     }
 }
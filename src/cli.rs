@@ -1,4 +1,4 @@
-use dialoguer::{Input, Password};
+use dialoguer::{Confirm, Input, Password};
 
 use crate::secrets::PasswordValue;
 
@@ -25,6 +25,43 @@ pub fn user_prompt(prompt: &str) -> Result<String, std::io::Error> {
     Ok(res.to_owned())
 }
 
+/// Ask the user to confirm the action.
+///
+/// # Parameters
+///
+/// * `prompt` - question that user answers yes or no
+///
+/// # Returns
+///
+/// true if the user has confirmed, no is the default answer
+pub fn confirm_prompt(prompt: &str) -> Result<bool, std::io::Error> {
+    Confirm::new()
+        .with_prompt(prompt)
+        .default(false)
+        .interact()
+        .map_err(to_io_error)
+}
+
+/// Print the user prompt text with the pre-filled value and wait user input.
+///
+/// # Parameters
+///
+/// * `prompt` - text what exactly is required from the user
+/// * `initial` - current value that user can edit
+///
+/// # Returns
+///
+/// string that user has entered, it might be empty
+pub fn user_prompt_with_initial(prompt: &str, initial: &str) -> Result<String, std::io::Error> {
+    let res = Input::<String>::new()
+        .with_prompt(prompt)
+        .with_initial_text(initial)
+        .allow_empty(true)
+        .interact_text()
+        .map_err(to_io_error)?;
+    Ok(res.trim().to_owned())
+}
+
 /// Print the user prompt text and wait user input. In comparison with [[user_propmt]] the user input will be not printed.
 ///
 /// # Parameters
@@ -45,3 +82,22 @@ pub fn password_prompt(prompt: &str) -> Result<PasswordValue, std::io::Error> {
 
     Ok(password)
 }
+
+/// Ask the new password twice and check both values are the same.
+///
+/// # Parameters
+///
+/// * `prompt` - text what exactly is required from the user
+///
+/// # Returns
+///
+/// password that user has entered
+pub fn new_password_prompt(prompt: &str) -> Result<PasswordValue, std::io::Error> {
+    let value = Password::new()
+        .with_prompt(prompt)
+        .with_confirmation("Please repeat it", "Values don't match")
+        .interact()
+        .map_err(to_io_error)?;
+
+    Ok(PasswordValue { value })
+}
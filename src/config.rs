@@ -1,49 +1,449 @@
-use clap::{Arg, Command};
+use clap::{Arg, ArgAction, Command};
 use config::ConfigError;
-use std::io;
+use std::{fmt::Display, io, str::FromStr};
+
+use regex::Regex;
+
+use crate::{
+    actions::{list_emails::parse_date, query::Query},
+    secrets::encryption::KdfParams,
+};
 
 pub mod userconfig;
 
 pub const COMMAND_INIT: &str = "init";
 pub const COMMAND_UPDATE_PASSWORD: &str = "update-password";
+pub const COMMAND_ROTATE_TOKEN: &str = "rotate-token";
+pub const COMMAND_KEY_MODE: &str = "key-mode";
 pub const COMMAND_REFRESH_DB: &str = "refresh-db";
 pub const COMMAND_EXPORT_LUA: &str = "export-lua";
 pub const COMMAND_SHOW_DB: &str = "show";
+pub const COMMAND_LIST: &str = "list";
+pub const COMMAND_GET: &str = "get";
+pub const COMMAND_CREATE: &str = "create";
+pub const COMMAND_ENABLE: &str = "enable";
+pub const COMMAND_DISABLE: &str = "disable";
+pub const COMMAND_DELETE: &str = "delete";
+pub const COMMAND_EDIT: &str = "edit";
+pub const COMMAND_MIGRATE_DB: &str = "migrate-db";
+pub const COMMAND_BACKUP: &str = "backup";
+pub const COMMAND_BACKUP_LIST: &str = "list";
+pub const COMMAND_BACKUP_RESTORE: &str = "restore";
+pub const COMMAND_PROFILES: &str = "profiles";
+pub const COMMAND_PROFILES_LIST: &str = "list";
+
+/// Name of the profile that is kept in the top level settings.
+pub const DEFAULT_PROFILE: &str = "default";
+
+/// Environment variable with the fastmail token for headless runs.
+pub const TOKEN_ENV_VAR: &str = "FASTMAIL_TOKEN";
 
 pub fn run_args() -> Command {
     let mut command = Command::new("masked-email-cli")
         .author("Sergei Grigorev")
         .about("App to see all masked emails created by FastMail service")
-        .subcommand(Command::new(COMMAND_INIT).about("Create or update the program configuration"))
+        .arg(
+            Arg::new("profile")
+                .long("profile")
+                .global(true)
+                .help("Fastmail account profile from the config, the top level settings are the `default` one"),
+        )
+        .arg(
+            Arg::new("secret-storage")
+                .long("secret-storage")
+                .global(true)
+                .value_parser([
+                    SECRET_STORAGE_KEYCHAIN,
+                    SECRET_STORAGE_FILE,
+                    SECRET_STORAGE_HELPER,
+                ])
+                .help("Where the fastmail token and AES key are stored, `init` keeps the choice in the config"),
+        )
+        .arg(
+            Arg::new("token-file")
+                .long("token-file")
+                .global(true)
+                .value_parser(clap::value_parser!(std::path::PathBuf))
+                .help("Read the fastmail token from the file (`-` is stdin) instead of the secret storage"),
+        )
+        .arg(
+            Arg::new("token-fd")
+                .long("token-fd")
+                .global(true)
+                .value_parser(clap::value_parser!(u32))
+                .conflicts_with("token-file")
+                .help("Read the fastmail token from the file descriptor instead of the secret storage"),
+        )
+        .subcommand(
+            Command::new(COMMAND_INIT)
+                .about("Create or update the program configuration")
+                .arg(
+                    Arg::new("kdf-memory-cost")
+                        .long("kdf-memory-cost")
+                        .value_parser(clap::value_parser!(u32))
+                        .help("Argon2 memory size in KiB for the new database key"),
+                )
+                .arg(
+                    Arg::new("kdf-iterations")
+                        .long("kdf-iterations")
+                        .value_parser(clap::value_parser!(u32))
+                        .help("Argon2 iterations for the new database key"),
+                )
+                .arg(
+                    Arg::new("kdf-parallelism")
+                        .long("kdf-parallelism")
+                        .value_parser(clap::value_parser!(u32))
+                        .help("Argon2 parallelism for the new database key"),
+                ),
+        )
         .subcommand(
             Command::new(COMMAND_UPDATE_PASSWORD)
                 .about("Store new fastmail password. The old record might be deleted"),
         )
+        .subcommand(
+            Command::new(COMMAND_ROTATE_TOKEN)
+                .about("Store new fastmail password and re-encrypt the database with the new key"),
+        )
+        .subcommand(
+            Command::new(COMMAND_KEY_MODE)
+                .about("Re-encrypt the database with the key derived from the token or a passphrase")
+                .arg(
+                    Arg::new("mode")
+                        .required(true)
+                        .value_parser(["token", "passphrase", "wrapped-key"])
+                        .help("Where the database key comes from"),
+                ),
+        )
         .subcommand(
             Command::new(COMMAND_REFRESH_DB)
-                .about("Download the whole emails list and update the database"),
+                .about("Download new and changed emails and update the database")
+                .arg(
+                    Arg::new("force")
+                        .long("force")
+                        .action(ArgAction::SetTrue)
+                        .help("Store the result even if many emails disappear or the token cannot decrypt the database, the previous version is kept as a backup"),
+                ),
         )
         .subcommand(
             Command::new(COMMAND_EXPORT_LUA)
                 .about("Export all email aliases using provided lua script")
-                .arg(Arg::new("path").short('p').required(true)),
+                .arg(Arg::new("path").short('p').required(true))
+                .arg(query_arg().long("query")),
+        )
+        .subcommand(
+            Command::new(COMMAND_SHOW_DB)
+                .about("Show all email aliases")
+                .arg(query_arg()),
+        )
+        .subcommand(
+            Command::new(COMMAND_LIST)
+                .about("Print email aliases from the database, filtered and in a machine readable format")
+                .arg(
+                    Arg::new("state")
+                        .long("state")
+                        .action(ArgAction::Append)
+                        .value_parser(["pending", "enabled", "disabled", "deleted"])
+                        .help("Aliases in the state, can be repeated"),
+                )
+                .arg(
+                    Arg::new("domain")
+                        .long("domain")
+                        .help("Website contains the text (case insensitive)"),
+                )
+                .arg(
+                    Arg::new("description")
+                        .long("description")
+                        .value_parser(|s: &str| Regex::new(s).map_err(|e| e.to_string()))
+                        .help("Description matches the regular expression"),
+                )
+                .arg(date_arg("created-after", "Created at or after the date"))
+                .arg(date_arg("created-before", "Created before the date"))
+                .arg(date_arg("last-message-after", "Last message was received at or after the date"))
+                .arg(date_arg(
+                    "last-message-before",
+                    "Last message was received before the date, or no messages at all",
+                ))
+                .arg(query_arg().long("query"))
+                .arg(
+                    Arg::new("sort")
+                        .long("sort")
+                        .value_parser(["email", "domain", "state", "created", "last-message"])
+                        .help("Field the aliases are sorted by"),
+                )
+                .arg(
+                    Arg::new("reverse")
+                        .long("reverse")
+                        .action(ArgAction::SetTrue)
+                        .help("Reverse the order"),
+                )
+                .arg(
+                    Arg::new("limit")
+                        .long("limit")
+                        .value_parser(clap::value_parser!(usize))
+                        .help("Print at most this number of aliases"),
+                )
+                .arg(format_arg()),
+        )
+        .subcommand(
+            Command::new(COMMAND_GET)
+                .about("Print the aliases found by the email address, ID or website")
+                .long_about("Print the aliases found by the email address, ID or website. The exit code is 1 when nothing is found and 2 on errors")
+                .arg(
+                    Arg::new("target")
+                        .required(true)
+                        .help("Email address, ID of the alias or website like example.com"),
+                )
+                .arg(format_arg()),
+        )
+        .subcommand(
+            Command::new(COMMAND_CREATE)
+                .about("Create a new email alias and add it to the database")
+                .arg(
+                    Arg::new("domain")
+                        .long("domain")
+                        .help("Web site the alias is created for"),
+                )
+                .arg(
+                    Arg::new("description")
+                        .long("description")
+                        .help("Short description of the alias"),
+                )
+                .arg(
+                    Arg::new("url")
+                        .long("url")
+                        .help("Deep link to the integration that created the alias"),
+                )
+                .arg(
+                    Arg::new("prefix")
+                        .long("prefix")
+                        .help("Prefix of the generated email address"),
+                )
+                .arg(
+                    Arg::new("state")
+                        .long("state")
+                        .value_parser(["pending", "enabled", "disabled"])
+                        .default_value("enabled")
+                        .help("Initial state of the alias"),
+                ),
+        )
+        .subcommand(
+            Command::new(COMMAND_ENABLE)
+                .about("Enable email aliases")
+                .arg(target_emails_arg())
+                .arg(query_arg().long("query").conflicts_with("emails"))
+                .arg(yes_arg()),
+        )
+        .subcommand(
+            Command::new(COMMAND_DISABLE)
+                .about("Disable email aliases, all new messages will be moved to trash")
+                .arg(target_emails_arg())
+                .arg(query_arg().long("query").conflicts_with("emails"))
+                .arg(yes_arg()),
+        )
+        .subcommand(
+            Command::new(COMMAND_DELETE)
+                .about("Delete email aliases, all new messages will be rejected")
+                .arg(target_emails_arg())
+                .arg(query_arg().long("query").conflicts_with("emails"))
+                .arg(yes_arg()),
+        )
+        .subcommand(
+            Command::new(COMMAND_EDIT)
+                .about("Edit description, domain and url of the email alias")
+                .long_about("Edit description, domain and url of the email alias. Without any flags an interactive form is shown. An empty value removes the field")
+                .arg(
+                    Arg::new("email")
+                        .required(true)
+                        .help("Email address or ID of the alias"),
+                )
+                .arg(
+                    Arg::new("domain")
+                        .long("domain")
+                        .help("Web site the alias is created for"),
+                )
+                .arg(
+                    Arg::new("description")
+                        .long("description")
+                        .help("Short description of the alias"),
+                )
+                .arg(
+                    Arg::new("url")
+                        .long("url")
+                        .help("Deep link to the integration that created the alias"),
+                ),
         )
-        .subcommand(Command::new(COMMAND_SHOW_DB).about("Show all email aliases"));
+        .subcommand(
+            Command::new(COMMAND_MIGRATE_DB)
+                .about("Rewrite the database in the latest file format"),
+        )
+        .subcommand(
+            Command::new(COMMAND_BACKUP)
+                .about("Manage previous versions of the database")
+                .subcommand_required(true)
+                .subcommand(Command::new(COMMAND_BACKUP_LIST).about("Show all database backups"))
+                .subcommand(
+                    Command::new(COMMAND_BACKUP_RESTORE)
+                        .about("Replace the database with the backup, the current version becomes the first backup")
+                        .arg(
+                            Arg::new("index")
+                                .required(true)
+                                .value_parser(clap::value_parser!(usize))
+                                .help("Backup index from the list, 1 is the most recent version"),
+                        ),
+                ),
+        )
+        .subcommand(
+            Command::new(COMMAND_PROFILES)
+                .about("Manage fastmail account profiles")
+                .subcommand_required(true)
+                .subcommand(Command::new(COMMAND_PROFILES_LIST).about("Show all configured profiles")),
+        );
 
     command.build();
     command
 }
 
+/// Date argument: `2024-01-31`, RFC 3339 time or the age like `90d`, `12w`, `6m`, `2y`.
+fn date_arg(name: &'static str, help: &'static str) -> Arg {
+    Arg::new(name)
+        .long(name)
+        .value_parser(parse_date)
+        .help(format!(
+            "{help}: YYYY-MM-DD, RFC 3339 or the age like 90d, 12w, 6m, 2y"
+        ))
+}
+
+/// Output format of the printed aliases.
+fn format_arg() -> Arg {
+    Arg::new("format")
+        .long("format")
+        .value_parser(["table", "json", "ndjson", "csv"])
+        .default_value("table")
+        .help("Output format")
+}
+
+/// Positional list of email addresses or fastmail IDs.
+fn target_emails_arg() -> Arg {
+    Arg::new("emails")
+        .required_unless_present("query")
+        .num_args(1..)
+        .help("Email addresses or IDs of the aliases")
+}
+
+/// Query like `state:enabled and domain:*.shop and last_message<90d`.
+fn query_arg() -> Arg {
+    Arg::new("query")
+        .value_parser(|s: &str| s.parse::<Query>().map_err(|e| e.to_string()))
+        .help("Only aliases that match the query, for instance `state:enabled and domain:*.shop and last_message<90d`")
+}
+
+/// Skip the confirmation of the bulk change.
+fn yes_arg() -> Arg {
+    Arg::new("yes")
+        .long("yes")
+        .short('y')
+        .action(ArgAction::SetTrue)
+        .requires("query")
+        .help("Change all aliases matched by the query without confirmation")
+}
+
+const SECRET_STORAGE_KEYCHAIN: &str = "keychain";
+const SECRET_STORAGE_FILE: &str = "file";
+const SECRET_STORAGE_HELPER: &str = "helper";
+
+/// Storage of the fastmail token and AES key.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum SecretStorageKind {
+    /// MacOS KeyChain
+    KeyChain,
+    /// File in the config directory encrypted with a passphrase
+    EncryptedFile,
+    /// External credential helper command
+    CredentialHelper,
+}
+
+impl Default for SecretStorageKind {
+    /// KeyChain is used on MacOS, the encrypted file on other platforms.
+    fn default() -> Self {
+        if cfg!(target_os = "macos") {
+            SecretStorageKind::KeyChain
+        } else {
+            SecretStorageKind::EncryptedFile
+        }
+    }
+}
+
+impl FromStr for SecretStorageKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            SECRET_STORAGE_KEYCHAIN => Ok(SecretStorageKind::KeyChain),
+            SECRET_STORAGE_FILE => Ok(SecretStorageKind::EncryptedFile),
+            SECRET_STORAGE_HELPER => Ok(SecretStorageKind::CredentialHelper),
+            other => Err(format!("unknown secret storage: {other}")),
+        }
+    }
+}
+
+impl Display for SecretStorageKind {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            SecretStorageKind::KeyChain => write!(f, "{SECRET_STORAGE_KEYCHAIN}"),
+            SecretStorageKind::EncryptedFile => write!(f, "{SECRET_STORAGE_FILE}"),
+            SecretStorageKind::CredentialHelper => write!(f, "{SECRET_STORAGE_HELPER}"),
+        }
+    }
+}
+
+#[derive(Clone)]
 pub struct AppConfig {
+    /// Profile name, empty for the default profile
+    pub profile: Option<String>,
     pub user_name: String,
     pub storage: String,
+    /// JMAP session endpoint, FastMail is used by default
+    pub session_url: Option<String>,
+    /// Disable TLS certificate verification
+    pub accept_invalid_certs: bool,
+    /// Path to the additional root certificate in PEM format
+    pub ca_certificate: Option<String>,
+    /// Fraction of emails that can disappear during one refresh without confirmation
+    pub max_removed_fraction: Option<f64>,
+    /// How many previous versions of the database are kept
+    pub backups: Option<usize>,
+    /// Key derivation parameters of new databases, existing ones keep parameters from the header
+    pub kdf_params: Option<KdfParams>,
+    /// Storage of the fastmail token and AES key, the platform default is used if that is not set
+    pub secret_storage: Option<SecretStorageKind>,
+    /// Command of the external credential helper
+    pub credential_helper: Option<String>,
+}
+
+impl AppConfig {
+    /// Name of the secret storage entries. It is the user name for the default profile,
+    /// so the secrets stored before profiles existed are still found.
+    pub fn account_name(&self) -> String {
+        match &self.profile {
+            Some(profile) => format!("{profile}/{}", self.user_name),
+            None => self.user_name.clone(),
+        }
+    }
+
+    /// Profile name to show the user.
+    pub fn profile_name(&self) -> &str {
+        self.profile.as_deref().unwrap_or(DEFAULT_PROFILE)
+    }
 }
 
 pub trait ConfigReader {
     /// Load app configuration.
     /// That function returns an error in case the file does not exists.
-    fn try_load() -> Result<AppConfig, ConfigError>;
+    fn try_load(&self) -> Result<AppConfig, ConfigError>;
 
     /// Create or update the configuration.
-    fn update(config: &AppConfig) -> Result<(), io::Error>;
+    fn update(&self, config: &AppConfig) -> Result<(), io::Error>;
+
+    /// Load all configured profiles, the default one goes first.
+    fn list_profiles(&self) -> Result<Vec<AppConfig>, ConfigError>;
 }
@@ -1,20 +1,52 @@
 use config::{Config, ConfigError};
-use std::io::prelude::*;
 use std::path::{Path, PathBuf};
-use std::{
-    fs::File,
-    io::{self, BufWriter},
-};
+use std::{fs, io};
+use toml_edit::{DocumentMut, Item, Table, TomlError, Value};
 
-use super::{AppConfig, ConfigReader};
+use crate::secrets::encryption::KdfParams;
 
-pub struct UserConfig();
+use super::{AppConfig, ConfigReader, SecretStorageKind, DEFAULT_PROFILE};
+
+/// Configuration in the TOML file of the config directory.
+/// Top level settings are the default profile, named profiles are `[profiles.<name>]` tables
+/// that replace some of them.
+pub struct UserConfig {
+    path: PathBuf,
+    /// selected profile, empty for the default one
+    profile: Option<String>,
+}
+
+impl Default for UserConfig {
+    fn default() -> Self {
+        UserConfig {
+            path: UserConfig::derive_config_path(),
+            profile: None,
+        }
+    }
+}
 
 const CONFIG_PATH: &str = "maskedemail-cli.toml";
+const PROFILES_SECTION: &str = "profiles";
 const USER_NAME_PARAM: &str = "user_name";
 const STORAGE_PARAM: &str = "storage";
+const SESSION_URL_PARAM: &str = "session_url";
+const ACCEPT_INVALID_CERTS_PARAM: &str = "accept_invalid_certs";
+const CA_CERTIFICATE_PARAM: &str = "ca_certificate";
+const MAX_REMOVED_FRACTION_PARAM: &str = "max_removed_fraction";
+const BACKUPS_PARAM: &str = "backups";
+const KDF_MEMORY_COST_PARAM: &str = "kdf_memory_cost";
+const KDF_ITERATIONS_PARAM: &str = "kdf_iterations";
+const KDF_PARALLELISM_PARAM: &str = "kdf_parallelism";
+const SECRET_STORAGE_PARAM: &str = "secret_storage";
+const CREDENTIAL_HELPER_PARAM: &str = "credential_helper";
 
 impl UserConfig {
+    /// Select the profile, `default` is the same as no profile.
+    pub fn with_profile(mut self, profile: Option<String>) -> Self {
+        self.profile = profile.filter(|name| name != DEFAULT_PROFILE);
+        self
+    }
+
     /// Get the full path for the config file.
     fn derive_config_path() -> PathBuf {
         match dirs::config_dir() {
@@ -27,46 +59,304 @@ impl UserConfig {
         }
     }
 
-    fn load(config_path: &Path) -> Result<AppConfig, ConfigError> {
+    fn read_settings(config_path: &Path) -> Result<Config, ConfigError> {
         let settings = Config::builder()
             .add_source(config::File::from(config_path))
             .add_source(config::Environment::with_prefix("APP"))
             .build()?;
         log::debug!("Config file exists, start to parse it");
+        Ok(settings)
+    }
+
+    fn load_profile(config_path: &Path, profile: Option<&str>) -> Result<AppConfig, ConfigError> {
+        let settings = UserConfig::read_settings(config_path)?;
+        UserConfig::parse(&settings, profile)
+    }
+
+    /// Load the default profile (if that is configured) and all named profiles.
+    fn load_all(config_path: &Path) -> Result<Vec<AppConfig>, ConfigError> {
+        let settings = UserConfig::read_settings(config_path)?;
 
-        let user_name = settings.get_string(USER_NAME_PARAM)?;
-        let storage = settings.get_string(STORAGE_PARAM)?;
-        Ok(AppConfig { user_name, storage })
+        let mut profiles = Vec::new();
+        if settings.get_string(USER_NAME_PARAM).is_ok() {
+            profiles.push(UserConfig::parse(&settings, None)?);
+        }
+
+        let mut names: Vec<String> = settings
+            .get_table(PROFILES_SECTION)
+            .map(|table| table.into_keys().collect())
+            .unwrap_or_default();
+        names.sort();
+        for name in names {
+            profiles.push(UserConfig::parse(&settings, Some(&name))?);
+        }
+        Ok(profiles)
     }
 
+    /// Read the profile setting, the top level one is used if the profile doesn't have it.
+    fn get<'de, T>(settings: &Config, profile: Option<&str>, name: &str) -> Result<T, ConfigError>
+    where
+        T: serde::Deserialize<'de>,
+    {
+        if let Some(profile) = profile {
+            match settings.get::<T>(&format!("{PROFILES_SECTION}.{profile}.{name}")) {
+                Err(ConfigError::NotFound(_)) => {}
+                found => return found,
+            }
+        }
+        settings.get::<T>(name)
+    }
+
+    fn parse(settings: &Config, profile: Option<&str>) -> Result<AppConfig, ConfigError> {
+        if let Some(name) = profile {
+            settings
+                .get_table(&format!("{PROFILES_SECTION}.{name}"))
+                .map_err(|_| ConfigError::NotFound(format!("profile {name}")))?;
+        }
+        let get_string = |name: &str| UserConfig::get::<String>(settings, profile, name);
+        let get_int = |name: &str| UserConfig::get::<i64>(settings, profile, name);
+
+        let user_name = get_string(USER_NAME_PARAM)?;
+        // every profile has its own database, the default location is the profile subdirectory
+        let storage = match profile {
+            Some(name) => settings
+                .get_string(&format!("{PROFILES_SECTION}.{name}.{STORAGE_PARAM}"))
+                .or_else(|_| {
+                    settings.get_string(STORAGE_PARAM).map(|root| {
+                        PathBuf::from(root)
+                            .join(name)
+                            .to_string_lossy()
+                            .into_owned()
+                    })
+                })?,
+            None => settings.get_string(STORAGE_PARAM)?,
+        };
+        let session_url = get_string(SESSION_URL_PARAM).ok();
+        let accept_invalid_certs =
+            UserConfig::get::<bool>(settings, profile, ACCEPT_INVALID_CERTS_PARAM).unwrap_or(false);
+        let ca_certificate = get_string(CA_CERTIFICATE_PARAM).ok();
+        let max_removed_fraction =
+            UserConfig::get::<f64>(settings, profile, MAX_REMOVED_FRACTION_PARAM).ok();
+        let backups = get_int(BACKUPS_PARAM)
+            .ok()
+            .and_then(|v| usize::try_from(v).ok());
+
+        // missing key derivation parameters are taken from the defaults
+        let kdf_param = |name: &str| get_int(name).ok().and_then(|v| u32::try_from(v).ok());
+        let kdf_params = match (
+            kdf_param(KDF_MEMORY_COST_PARAM),
+            kdf_param(KDF_ITERATIONS_PARAM),
+            kdf_param(KDF_PARALLELISM_PARAM),
+        ) {
+            (None, None, None) => None,
+            (memory_cost, iterations, parallelism) => {
+                let defaults = KdfParams::default();
+                let kdf_params = KdfParams {
+                    memory_cost: memory_cost.unwrap_or(defaults.memory_cost),
+                    iterations: iterations.unwrap_or(defaults.iterations),
+                    parallelism: parallelism.unwrap_or(defaults.parallelism),
+                    ..defaults
+                };
+                kdf_params.validate().map_err(|e| {
+                    ConfigError::Message(format!("key derivation parameters are not valid: {e}"))
+                })?;
+                Some(kdf_params)
+            }
+        };
+        let secret_storage = get_string(SECRET_STORAGE_PARAM)
+            .ok()
+            .map(|s| s.parse::<SecretStorageKind>())
+            .transpose()
+            .map_err(ConfigError::Message)?;
+
+        let credential_helper = get_string(CREDENTIAL_HELPER_PARAM).ok();
+
+        Ok(AppConfig {
+            profile: profile.map(str::to_owned),
+            user_name,
+            storage,
+            session_url,
+            accept_invalid_certs,
+            ca_certificate,
+            max_removed_fraction,
+            backups,
+            kdf_params,
+            secret_storage,
+            credential_helper,
+        })
+    }
+
+    /// Write the profile, other profiles and the rest of the file are kept. Settings that the profile
+    /// inherits from the top level are not copied to its table.
     fn update(config: &AppConfig, config_path: &Path) -> Result<(), io::Error> {
-        let file = File::create(config_path)?;
-        let mut buf_writer = BufWriter::new(file);
-        writeln!(buf_writer, "{} = \"{}\"", USER_NAME_PARAM, config.user_name)?;
-        writeln!(buf_writer, "{} = \"{}\"", STORAGE_PARAM, config.storage)?;
-        buf_writer.flush()?;
+        let invalid_data = |e: String| io::Error::new(io::ErrorKind::InvalidData, e);
+
+        let mut document: DocumentMut = if config_path.exists() {
+            fs::read_to_string(config_path)?
+                .parse()
+                .map_err(|e: TomlError| invalid_data(e.to_string()))?
+        } else {
+            DocumentMut::new()
+        };
 
-        Ok(())
+        let inherited = match &config.profile {
+            Some(name) => UserConfig::inherited_settings(&document, name),
+            None => None,
+        };
+        let table = match &config.profile {
+            Some(name) => document
+                .entry(PROFILES_SECTION)
+                .or_insert_with(|| {
+                    let mut profiles = Table::new();
+                    profiles.set_implicit(true);
+                    Item::Table(profiles)
+                })
+                .as_table_mut()
+                .and_then(|profiles| {
+                    profiles
+                        .entry(name)
+                        .or_insert_with(|| Item::Table(Table::new()))
+                        .as_table_mut()
+                })
+                .ok_or_else(|| invalid_data(format!("{PROFILES_SECTION}.{name} is not a table")))?,
+            None => document.as_table_mut(),
+        };
+        UserConfig::write_settings(table, config, inherited.as_ref());
+
+        fs::write(config_path, document.to_string())
+    }
+
+    /// Settings that the profile gets from the top level when its own table is empty.
+    ///
+    /// # Returns
+    ///
+    /// empty if the top level doesn't have the required settings
+    fn inherited_settings(document: &DocumentMut, profile: &str) -> Option<AppConfig> {
+        let mut document = document.clone();
+        let mut profiles = Table::new();
+        profiles.insert(profile, Item::Table(Table::new()));
+        document.insert(PROFILES_SECTION, Item::Table(profiles));
+
+        let settings = Config::builder()
+            .add_source(config::File::from_str(
+                &document.to_string(),
+                config::FileFormat::Toml,
+            ))
+            .build()
+            .ok()?;
+        UserConfig::parse(&settings, Some(profile)).ok()
+    }
+
+    /// Set the setting in the table. Settings of the table are always kept, new ones are added only
+    /// if they are not inherited.
+    fn set_value<T>(table: &mut Table, name: &str, value: Option<T>, inherited: Option<T>)
+    where
+        T: PartialEq + Into<Value>,
+    {
+        match value {
+            Some(value) if table.contains_key(name) || inherited.as_ref() != Some(&value) => {
+                // the key keeps its comments
+                table[name] = toml_edit::value(value);
+            }
+            Some(_) => {}
+            None => {
+                table.remove(name);
+            }
+        }
+    }
+
+    fn write_settings(table: &mut Table, config: &AppConfig, inherited: Option<&AppConfig>) {
+        let kdf_param = |config: &AppConfig, param: fn(&KdfParams) -> u32| {
+            config.kdf_params.as_ref().map(|p| i64::from(param(p)))
+        };
+
+        UserConfig::set_value(
+            table,
+            USER_NAME_PARAM,
+            Some(config.user_name.clone()),
+            inherited.map(|c| c.user_name.clone()),
+        );
+        UserConfig::set_value(
+            table,
+            STORAGE_PARAM,
+            Some(config.storage.clone()),
+            inherited.map(|c| c.storage.clone()),
+        );
+        UserConfig::set_value(
+            table,
+            SESSION_URL_PARAM,
+            config.session_url.clone(),
+            inherited.and_then(|c| c.session_url.clone()),
+        );
+        UserConfig::set_value(
+            table,
+            ACCEPT_INVALID_CERTS_PARAM,
+            Some(config.accept_invalid_certs).filter(|accept| *accept),
+            inherited.map(|c| c.accept_invalid_certs),
+        );
+        UserConfig::set_value(
+            table,
+            CA_CERTIFICATE_PARAM,
+            config.ca_certificate.clone(),
+            inherited.and_then(|c| c.ca_certificate.clone()),
+        );
+        UserConfig::set_value(
+            table,
+            MAX_REMOVED_FRACTION_PARAM,
+            config.max_removed_fraction,
+            inherited.and_then(|c| c.max_removed_fraction),
+        );
+        UserConfig::set_value(
+            table,
+            BACKUPS_PARAM,
+            config.backups.map(|v| v as i64),
+            inherited.and_then(|c| c.backups).map(|v| v as i64),
+        );
+        for (name, param) in [
+            (
+                KDF_MEMORY_COST_PARAM,
+                (|p| p.memory_cost) as fn(&KdfParams) -> u32,
+            ),
+            (KDF_ITERATIONS_PARAM, |p| p.iterations),
+            (KDF_PARALLELISM_PARAM, |p| p.parallelism),
+        ] {
+            UserConfig::set_value(
+                table,
+                name,
+                kdf_param(config, param),
+                inherited.and_then(|c| kdf_param(c, param)),
+            );
+        }
+        UserConfig::set_value(
+            table,
+            SECRET_STORAGE_PARAM,
+            config.secret_storage.map(|s| s.to_string()),
+            inherited
+                .and_then(|c| c.secret_storage)
+                .map(|s| s.to_string()),
+        );
+        UserConfig::set_value(
+            table,
+            CREDENTIAL_HELPER_PARAM,
+            config.credential_helper.clone(),
+            inherited.and_then(|c| c.credential_helper.clone()),
+        );
     }
 }
 
 impl ConfigReader for UserConfig {
-    fn try_load() -> Result<AppConfig, ConfigError> {
-        let config_path: PathBuf = UserConfig::derive_config_path();
-        log::info!(
-            "Attempt to read the config from [{}]",
-            config_path.display()
-        );
-        UserConfig::load(config_path.as_path())
+    fn try_load(&self) -> Result<AppConfig, ConfigError> {
+        log::info!("Attempt to read the config from [{}]", self.path.display());
+        UserConfig::load_profile(self.path.as_path(), self.profile.as_deref())
     }
 
-    fn update(config: &AppConfig) -> Result<(), io::Error> {
-        let config_path: PathBuf = UserConfig::derive_config_path();
-        log::info!(
-            "Configuration will be stored in: [{}]",
-            config_path.display()
-        );
-        UserConfig::update(config, config_path.as_path())
+    fn update(&self, config: &AppConfig) -> Result<(), io::Error> {
+        log::info!("Configuration will be stored in: [{}]", self.path.display());
+        UserConfig::update(config, self.path.as_path())
+    }
+
+    fn list_profiles(&self) -> Result<Vec<AppConfig>, ConfigError> {
+        UserConfig::load_all(self.path.as_path())
     }
 }
 
@@ -74,7 +364,10 @@ impl ConfigReader for UserConfig {
 mod tests {
     use std::path::PathBuf;
 
-    use crate::config::AppConfig;
+    use crate::{
+        config::{AppConfig, SecretStorageKind},
+        secrets::encryption::KdfParams,
+    };
 
     use super::UserConfig;
 
@@ -87,15 +380,209 @@ mod tests {
         tmp_file.push("test_config.toml");
 
         let sample = AppConfig {
+            profile: None,
             user_name: "my_user@example.com".to_owned(),
             storage: tmp_dir.path().to_str().unwrap().to_owned(),
+            session_url: None,
+            accept_invalid_certs: false,
+            ca_certificate: None,
+            max_removed_fraction: None,
+            backups: None,
+            kdf_params: None,
+            secret_storage: None,
+            credential_helper: None,
         };
 
         UserConfig::update(&sample, &tmp_file).unwrap();
 
-        let reloaded = UserConfig::load(&tmp_file).unwrap();
+        let reloaded = UserConfig::load_profile(&tmp_file, None).unwrap();
 
         assert_eq!(reloaded.user_name, sample.user_name);
         assert_eq!(reloaded.storage, sample.storage);
+        assert_eq!(reloaded.session_url, None);
+        assert!(!reloaded.accept_invalid_certs);
+        assert_eq!(reloaded.ca_certificate, None);
+        assert_eq!(reloaded.max_removed_fraction, None);
+        assert_eq!(reloaded.backups, None);
+        assert_eq!(reloaded.kdf_params, None);
+        assert_eq!(reloaded.secret_storage, None);
+        assert_eq!(reloaded.credential_helper, None);
+    }
+
+    #[test]
+    fn check_the_optional_settings() {
+        // make new tmp directory
+        let tmp_dir = tempfile::tempdir().unwrap();
+
+        let mut tmp_file = PathBuf::from(tmp_dir.path());
+        tmp_file.push("test_config.toml");
+
+        let sample = AppConfig {
+            profile: None,
+            user_name: "my_user@example.com".to_owned(),
+            storage: tmp_dir.path().to_str().unwrap().to_owned(),
+            session_url: Some("https://localhost:8443/jmap/session".to_owned()),
+            accept_invalid_certs: true,
+            ca_certificate: Some("/etc/ssl/local-ca.pem".to_owned()),
+            max_removed_fraction: Some(0.5),
+            backups: Some(5),
+            kdf_params: Some(KdfParams {
+                memory_cost: 65536,
+                iterations: 4,
+                ..KdfParams::default()
+            }),
+            secret_storage: Some(SecretStorageKind::CredentialHelper),
+            credential_helper: Some("pass-helper --store \"masked email\"".to_owned()),
+        };
+
+        UserConfig::update(&sample, &tmp_file).unwrap();
+
+        let reloaded = UserConfig::load_profile(&tmp_file, None).unwrap();
+
+        assert_eq!(reloaded.session_url, sample.session_url);
+        assert!(reloaded.accept_invalid_certs);
+        assert_eq!(reloaded.ca_certificate, sample.ca_certificate);
+        assert_eq!(reloaded.max_removed_fraction, sample.max_removed_fraction);
+        assert_eq!(reloaded.backups, sample.backups);
+        assert_eq!(reloaded.kdf_params, sample.kdf_params);
+        assert_eq!(reloaded.secret_storage, sample.secret_storage);
+        assert_eq!(reloaded.credential_helper, sample.credential_helper);
+    }
+
+    #[test]
+    fn weak_kdf_params_are_rejected() {
+        // make new tmp directory
+        let tmp_dir = tempfile::tempdir().unwrap();
+
+        let mut tmp_file = PathBuf::from(tmp_dir.path());
+        tmp_file.push("test_config.toml");
+        std::fs::write(
+            &tmp_file,
+            r#"user_name = "me@example.com"
+storage = "/data"
+kdf_memory_cost = 8
+kdf_iterations = 1
+"#,
+        )
+        .unwrap();
+
+        assert!(UserConfig::load_profile(&tmp_file, None).is_err());
+    }
+
+    #[test]
+    fn profiles_extend_the_default_settings() {
+        // make new tmp directory
+        let tmp_dir = tempfile::tempdir().unwrap();
+
+        let mut tmp_file = PathBuf::from(tmp_dir.path());
+        tmp_file.push("test_config.toml");
+        std::fs::write(
+            &tmp_file,
+            r#"user_name = "me@example.com"
+storage = "/data"
+backups = 5
+
+[profiles.work]
+user_name = "team@example.com"
+
+[profiles.lab]
+user_name = "lab@example.com"
+storage = "/lab"
+backups = 1
+"#,
+        )
+        .unwrap();
+
+        let default = UserConfig::load_profile(&tmp_file, None).unwrap();
+        assert_eq!(default.profile, None);
+        assert_eq!(default.account_name(), "me@example.com");
+
+        let work = UserConfig::load_profile(&tmp_file, Some("work")).unwrap();
+        assert_eq!(work.user_name, "team@example.com");
+        assert_eq!(work.account_name(), "work/team@example.com");
+        assert_eq!(
+            PathBuf::from(&work.storage),
+            PathBuf::from("/data").join("work")
+        );
+        assert_eq!(work.backups, Some(5));
+        assert!(UserConfig::load_profile(&tmp_file, Some("missing")).is_err());
+
+        // the updated profile replaces only its own settings
+        let lab = AppConfig {
+            backups: Some(2),
+            ..UserConfig::load_profile(&tmp_file, Some("lab")).unwrap()
+        };
+        UserConfig::update(&lab, &tmp_file).unwrap();
+
+        let profiles: Vec<Option<String>> = UserConfig::load_all(&tmp_file)
+            .unwrap()
+            .into_iter()
+            .map(|p| p.profile)
+            .collect();
+        assert_eq!(
+            profiles,
+            vec![None, Some("lab".to_owned()), Some("work".to_owned())]
+        );
+        assert_eq!(
+            UserConfig::load_profile(&tmp_file, None).unwrap().backups,
+            Some(5)
+        );
+        let lab = UserConfig::load_profile(&tmp_file, Some("lab")).unwrap();
+        assert_eq!(lab.storage, "/lab");
+        assert_eq!(lab.backups, Some(2));
+    }
+
+    #[test]
+    fn saved_profile_keeps_inherited_settings() {
+        // make new tmp directory
+        let tmp_dir = tempfile::tempdir().unwrap();
+
+        let mut tmp_file = PathBuf::from(tmp_dir.path());
+        tmp_file.push("test_config.toml");
+        std::fs::write(
+            &tmp_file,
+            r#"# shared settings
+user_name = "me@example.com"
+storage = "/data"
+backups = 5
+
+[profiles.work]
+user_name = "team@example.com"
+
+[profiles.lab]
+user_name = "lab@example.com"
+"#,
+        )
+        .unwrap();
+
+        let work = AppConfig {
+            max_removed_fraction: Some(0.2),
+            ..UserConfig::load_profile(&tmp_file, Some("work")).unwrap()
+        };
+        UserConfig::update(&work, &tmp_file).unwrap();
+
+        // the top level change reaches both profiles
+        let default = AppConfig {
+            backups: Some(7),
+            ..UserConfig::load_profile(&tmp_file, None).unwrap()
+        };
+        UserConfig::update(&default, &tmp_file).unwrap();
+
+        let work = UserConfig::load_profile(&tmp_file, Some("work")).unwrap();
+        assert_eq!(work.backups, Some(7));
+        assert_eq!(work.max_removed_fraction, Some(0.2));
+        assert_eq!(
+            PathBuf::from(&work.storage),
+            PathBuf::from("/data").join("work")
+        );
+        let lab = UserConfig::load_profile(&tmp_file, Some("lab")).unwrap();
+        assert_eq!(lab.user_name, "lab@example.com");
+        assert_eq!(lab.backups, Some(7));
+        assert_eq!(lab.max_removed_fraction, None);
+
+        let content = std::fs::read_to_string(&tmp_file).unwrap();
+        assert!(content.starts_with("# shared settings"));
+        assert_eq!(content.matches("backups").count(), 1);
+        assert_eq!(content.matches("storage").count(), 1);
     }
 }
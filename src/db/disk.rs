@@ -1,35 +1,85 @@
 use serde::{Deserialize, Serialize};
-use std::io::{BufReader, BufWriter};
-use std::u32;
+use std::io::BufWriter;
 use std::{fs, path::PathBuf};
 use thiserror::Error;
 
 use chrono::{DateTime, Utc};
 
+use crate::model::masked_email::MaskedEmail;
 use crate::secrets::encryption::{
-    decrypt_in_place, encrypt_in_place, EncryptionNonce, EncryptionTag, KeyDerivationSalt,
-    NONCE_SIZE_BYTES,
+    decrypt_in_place, encrypt_in_place, EncryptionNonce, EncryptionTag, KdfParams,
+    KeyDerivationSalt, WrappedKey, NONCE_SIZE_BYTES,
 };
 
 const DATABASE_FILE_NAME: &str = "masked_mails.db.enc";
-const FILE_SIGNATURE: [u8; 4] = [b'M', b'E', b'F', 1u8];
+const TEMP_FILE_EXTENSION: &str = "tmp";
+const BACKUP_FILE_EXTENSION: &str = "bak";
+pub const DEFAULT_BACKUPS_COUNT: usize = 3;
+const FILE_SIGNATURE_PREFIX: [u8; 3] = [b'M', b'E', b'F'];
+const FILE_SIGNATURE_V1: [u8; 4] = [b'M', b'E', b'F', 1u8];
+const FILE_SIGNATURE_V2: [u8; 4] = [b'M', b'E', b'F', 2u8];
+const FILE_SIGNATURE_V3: [u8; 4] = [b'M', b'E', b'F', 3u8];
+/// Format version of all new files
+pub const FORMAT_VERSION: u8 = 3;
+/// Version of the encrypted records payload
+pub const SCHEMA_VERSION: u16 = 1;
 
-/// File format specification:
-/// ### first 28 bytes are preloaded
-/// - file signature 4 bytes
+/// File format specification (v3):
+/// ### preloaded header
+/// - file signature 4 bytes (`MEF` + format version)
 /// - AES key nonce 12 bytes
-/// - last updated TS (8 bytes)
+/// - last updated TS
 /// - records count (4 bytes)
+/// - key derivation algorithm and its parameters
+/// - schema version of the records payload (2 bytes)
+/// - source of the AES key (the wrapped key itself for the wrapped key mode)
 /// ### others are not a part of the preloaded header
 /// - unique nonce 12 bytes
-/// - tag 16 bytes (from last updated + records count)
+/// - tag 16 bytes (the whole header is the associated data)
 /// - total encrypted block bytes length (8 bytes)
 /// - encrypted block (see the size above)
+///
+/// The encrypted block contains the emails list followed by the optional JMAP state.
+///
+/// v2 files have no key source, their key is always derived from the fastmail token.
+/// v1 files have no key derivation parameters and schema version, their tag is calculated from
+/// last updated + records count only. v1 files written before the JMAP state was introduced contain
+/// the emails list only.
 pub struct Database {
     path: PathBuf,
     pub key_derivation_salt: KeyDerivationSalt,
     pub last_update: DateTime<Utc>,
     pub records_count: u32,
+    /// file format version, the next update always writes the latest one
+    pub format_version: u8,
+    /// parameters the AES key is derived with
+    pub kdf_params: KdfParams,
+    /// version of the records payload
+    pub schema_version: u16,
+    /// where the AES key comes from
+    pub key_source: KeySource,
+    /// how many previous versions are kept next to the database
+    backups_count: usize,
+}
+
+/// Source of the database AES key, the key itself is never stored in the file.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub enum KeySource {
+    /// derived from the fastmail token
+    #[default]
+    Token,
+    /// derived from the user passphrase
+    Passphrase,
+    /// random key encrypted with the key derived from the user passphrase
+    WrappedKey(WrappedKey),
+}
+
+/// Previous version of the database.
+pub struct Backup {
+    /// 1 is the most recent version
+    pub index: usize,
+    /// backup header or the reason why it cannot be opened
+    pub snapshot: Result<Database>,
 }
 
 #[derive(Debug, Error)]
@@ -38,20 +88,150 @@ pub enum DBError {
     FileNotFound(String),
     #[error("file has an incorrect format")]
     IncorrectFileFormat,
+    #[error("file format version {0} is not supported, please update the application")]
+    UnsupportedFormatVersion(u8),
+    #[error("records schema version {0} is not supported, please update the application")]
+    UnsupportedSchemaVersion(u16),
     #[error("encoding error")]
     EncodingError,
     #[error("decoding error")]
     DecodingError,
     #[error("Disk IO error")]
     IOError(#[from] std::io::Error),
+    #[error("backup {0} is not found")]
+    BackupNotFound(usize),
+    #[error("file header has {expected} records, but the file contains {found}")]
+    RecordsCountMismatch { expected: u32, found: usize },
 }
 
+/// Header of the v1 files.
 #[derive(Deserialize, Serialize)]
-struct FileHeader {
+struct FileHeaderV1 {
+    file_signature: [u8; 4],
+    nonce: [u8; NONCE_SIZE_BYTES],
+    last_updated: DateTime<Utc>,
+    records_count: u32,
+}
+
+/// Header of the v2 files.
+#[derive(Deserialize, Serialize)]
+struct FileHeaderV2 {
     file_signature: [u8; 4],
     nonce: [u8; NONCE_SIZE_BYTES],
     last_updated: DateTime<Utc>,
     records_count: u32,
+    kdf_params: KdfParams,
+    schema_version: u16,
+}
+
+/// Header of the v3 files.
+#[derive(Deserialize, Serialize)]
+struct FileHeaderV3 {
+    file_signature: [u8; 4],
+    nonce: [u8; NONCE_SIZE_BYTES],
+    last_updated: DateTime<Utc>,
+    records_count: u32,
+    kdf_params: KdfParams,
+    schema_version: u16,
+    key_source: KeySource,
+}
+
+/// Header of any supported version.
+struct FileHeader {
+    format_version: u8,
+    nonce: [u8; NONCE_SIZE_BYTES],
+    last_updated: DateTime<Utc>,
+    records_count: u32,
+    kdf_params: KdfParams,
+    schema_version: u16,
+    key_source: KeySource,
+    /// data authenticated together with the encrypted block
+    associated_data: Vec<u8>,
+    /// header size in bytes, the encrypted part starts after it
+    size: usize,
+}
+
+impl FileHeader {
+    /// Parse the header from the beginning of the file.
+    fn parse(content: &[u8]) -> Result<Self> {
+        match content.get(..4) {
+            Some(signature) if signature == FILE_SIGNATURE_V1 => {
+                let header: FileHeaderV1 = bincode::deserialize(content).map_err(|e| {
+                    log::error!("File header cannot be deserialized: {:?}", e.as_ref());
+                    DBError::IncorrectFileFormat
+                })?;
+                Ok(FileHeader {
+                    format_version: 1,
+                    nonce: header.nonce,
+                    last_updated: header.last_updated,
+                    records_count: header.records_count,
+                    // v1 keys are always derived with the default parameters
+                    kdf_params: KdfParams::default(),
+                    schema_version: SCHEMA_VERSION,
+                    key_source: KeySource::Token,
+                    associated_data: bincode::serialize(&(
+                        header.last_updated,
+                        header.records_count,
+                    ))
+                    .expect("Error is not expected"),
+                    size: serialized_size(&header)?,
+                })
+            }
+            Some(signature) if signature == FILE_SIGNATURE_V2 => {
+                let header: FileHeaderV2 = bincode::deserialize(content).map_err(|e| {
+                    log::error!("File header cannot be deserialized: {:?}", e.as_ref());
+                    DBError::IncorrectFileFormat
+                })?;
+                Ok(FileHeader {
+                    format_version: 2,
+                    nonce: header.nonce,
+                    last_updated: header.last_updated,
+                    records_count: header.records_count,
+                    kdf_params: header.kdf_params,
+                    schema_version: header.schema_version,
+                    key_source: KeySource::Token,
+                    associated_data: bincode::serialize(&header).expect("Error is not expected"),
+                    size: serialized_size(&header)?,
+                })
+            }
+            Some(signature) if signature == FILE_SIGNATURE_V3 => {
+                let header: FileHeaderV3 = bincode::deserialize(content).map_err(|e| {
+                    log::error!("File header cannot be deserialized: {:?}", e.as_ref());
+                    DBError::IncorrectFileFormat
+                })?;
+                Ok(FileHeader {
+                    format_version: 3,
+                    nonce: header.nonce,
+                    last_updated: header.last_updated,
+                    records_count: header.records_count,
+                    kdf_params: header.kdf_params,
+                    schema_version: header.schema_version,
+                    associated_data: bincode::serialize(&header).expect("Error is not expected"),
+                    size: serialized_size(&header)?,
+                    key_source: header.key_source,
+                })
+            }
+            Some([m, e, f, version]) if [*m, *e, *f] == FILE_SIGNATURE_PREFIX => {
+                Err(DBError::UnsupportedFormatVersion(*version))
+            }
+            _ => Err(DBError::IncorrectFileFormat),
+        }
+    }
+}
+
+fn serialized_size<T: Serialize>(header: &T) -> Result<usize> {
+    bincode::serialized_size(header)
+        .ok()
+        .and_then(|size| usize::try_from(size).ok())
+        .ok_or(DBError::IncorrectFileFormat)
+}
+
+/// Decrypted database content.
+#[derive(Default)]
+pub struct DatabaseContent {
+    pub emails: Vec<MaskedEmail>,
+    /// JMAP state the emails were synchronized at
+    pub jmap_state: Option<String>,
 }
 
 pub type Result<A> = std::result::Result<A, DBError>;
@@ -62,34 +242,27 @@ impl Database {
     pub fn init<P: Into<std::path::PathBuf>>(path: P) -> Result<Self> {
         let mut full_path: PathBuf = path.into();
         full_path.push(DATABASE_FILE_NAME);
+        Database::open(full_path)
+    }
+
+    /// Read the header of the database file (or its backup).
+    fn open(full_path: PathBuf) -> Result<Self> {
         if full_path.exists() {
             match fs::read(full_path.as_path()) {
-                Ok(_) => {
-                    // read file header only
-                    let file = fs::File::open(full_path.as_path()).map_err(DBError::IOError)?;
-                    let buffer = BufReader::new(file);
-
-                    // deserialize file header
-                    let header: bincode::Result<FileHeader> = bincode::deserialize_from(buffer);
-                    match header {
-                        Ok(header) => {
-                            if header.file_signature == FILE_SIGNATURE {
-                                // parse last updated_ts and records count
-                                Ok(Database {
-                                    path: full_path,
-                                    key_derivation_salt: header.nonce,
-                                    last_update: header.last_updated,
-                                    records_count: header.records_count,
-                                })
-                            } else {
-                                Err(DBError::IncorrectFileFormat)
-                            }
-                        }
-                        Err(e) => {
-                            log::error!("File header cannot be deserialized: {:?}", e.as_ref());
-                            Err(DBError::IncorrectFileFormat)
-                        }
-                    }
+                Ok(content) => {
+                    // parse last updated_ts and records count
+                    let header = FileHeader::parse(&content)?;
+                    Ok(Database {
+                        path: full_path,
+                        key_derivation_salt: header.nonce,
+                        last_update: header.last_updated,
+                        records_count: header.records_count,
+                        format_version: header.format_version,
+                        kdf_params: header.kdf_params,
+                        schema_version: header.schema_version,
+                        key_source: header.key_source,
+                        backups_count: DEFAULT_BACKUPS_COUNT,
+                    })
                 }
                 Err(err) => {
                     log::error!(
@@ -117,78 +290,177 @@ impl Database {
             key_derivation_salt: nonce,
             last_update: Utc::now(),
             records_count: 0,
+            format_version: FORMAT_VERSION,
+            kdf_params: KdfParams::default(),
+            schema_version: SCHEMA_VERSION,
+            key_source: KeySource::Token,
+            backups_count: DEFAULT_BACKUPS_COUNT,
         }
     }
 
+    /// Change parameters the AES key of the new database is derived with.
+    pub fn with_kdf_params(mut self, kdf_params: KdfParams) -> Self {
+        self.kdf_params = kdf_params;
+        self
+    }
+
+    /// Change the source of the AES key of the new database.
+    pub fn with_key_source(mut self, key_source: KeySource) -> Self {
+        self.key_source = key_source;
+        self
+    }
+
+    /// Change how many previous versions are kept on every update, 0 disables backups.
+    pub fn with_backups(mut self, backups_count: usize) -> Self {
+        self.backups_count = backups_count;
+        self
+    }
+
+    /// List all backups of the database, the most recent goes first.
+    pub fn backups(&self) -> Result<Vec<Backup>> {
+        let mut indexes = self.backup_indexes()?;
+        indexes.sort_unstable();
+
+        Ok(indexes
+            .into_iter()
+            .map(|index| Backup {
+                index,
+                snapshot: Database::open(self.backup_path(index)),
+            })
+            .collect())
+    }
+
+    /// Replace the database with the backup. The content is decrypted and must have as many records as the
+    /// backup header before the database is replaced. The current version becomes the most recent backup.
+    ///
+    /// # Arguments
+    ///
+    /// * `index` - backup index, 1 is the most recent version
+    /// * `key` - AES encryption key
+    pub fn restore(&self, index: usize, key: &crate::secrets::AesKeyValue) -> Result<()> {
+        let backup_path = self.backup_path(index);
+        if !backup_path.exists() {
+            return Err(DBError::BackupNotFound(index));
+        }
+
+        // check the header and that the backup can be decrypted
+        let snapshot = Database::open(backup_path.clone())?;
+        let content = snapshot.load_content(key)?;
+        if usize::try_from(snapshot.records_count).ok() != Some(content.emails.len()) {
+            return Err(DBError::RecordsCountMismatch {
+                expected: snapshot.records_count,
+                found: content.emails.len(),
+            });
+        }
+        log::info!(
+            "Backup {index} has {} emails, last updated at {}",
+            content.emails.len(),
+            snapshot.last_update
+        );
+
+        // the backup is copied as it is going to be rotated
+        let temp_path = self
+            .path
+            .with_extension(format!("enc.{TEMP_FILE_EXTENSION}"));
+        fs::copy(&backup_path, &temp_path).map_err(DBError::IOError)?;
+        fs::File::open(&temp_path)
+            .and_then(|file| file.sync_all())
+            .map_err(DBError::IOError)?;
+
+        self.replace_with(&temp_path)
+    }
+
     /// load the database and all emails that it has. email and password are used to derive the encryption key.
     ///
     /// # arguments
     ///
     /// * `key` - aes encryption key
-    pub fn load(
-        &self,
-        key: &crate::secrets::AesKeyValue,
-    ) -> Result<Vec<crate::model::masked_email::MaskedEmail>> {
+    pub fn load(&self, key: &crate::secrets::AesKeyValue) -> Result<Vec<MaskedEmail>> {
+        self.load_content(key).map(|content| content.emails)
+    }
+
+    /// load the database content: all emails and the JMAP state. The stored file is always decrypted, even
+    /// without records, so the wrong key is reported for an empty database too.
+    ///
+    /// # arguments
+    ///
+    /// * `key` - aes encryption key
+    pub fn load_content(&self, key: &crate::secrets::AesKeyValue) -> Result<DatabaseContent> {
         use std::io::Read;
 
-        if self.records_count > 0 {
-            // read file header only
-            let file = fs::File::open(self.path.as_path()).map_err(DBError::IOError)?;
-            let mut buffer = BufReader::new(file);
+        if self.path.exists() {
+            let content = fs::read(self.path.as_path()).map_err(DBError::IOError)?;
+            let file_header = FileHeader::parse(&content)?;
+            if file_header.schema_version > SCHEMA_VERSION {
+                return Err(DBError::UnsupportedSchemaVersion(
+                    file_header.schema_version,
+                ));
+            }
 
             // skip file header
-            let header: bincode::Result<FileHeader> = bincode::deserialize_from(&mut buffer);
-            if let Ok(file_header) = header {
-                let associated_data =
-                    bincode::serialize(&(file_header.last_updated, file_header.records_count))
-                        .expect("Error is not expected");
-
-                // - unique nonce 12 bytes
-                let mut nonce: EncryptionNonce = Default::default();
-                buffer.read_exact(&mut nonce).map_err(DBError::IOError)?;
-
-                // - tag 16 bytes
-                let mut tag: EncryptionTag = Default::default();
-                buffer.read_exact(&mut tag).map_err(DBError::IOError)?;
-
-                // - total encrypted block bytes length (8 bytes)
-                let block_size: u64 =
-                    bincode::deserialize_from(&mut buffer).map_err(|_| DBError::DecodingError)?;
-                let block_size =
-                    usize::try_from(block_size).expect("File size is too big for that platform");
-
-                // - encrypted block (see the size above)
-                let mut encrypted_blob: Vec<u8> = Vec::<u8>::with_capacity(block_size);
-                buffer
-                    .read_to_end(&mut encrypted_blob)
-                    .map_err(DBError::IOError)?;
-                if encrypted_blob.len() == block_size {
-                    // decrypt the blob
-                    decrypt_in_place(key, &nonce, &associated_data, &mut encrypted_blob, &tag)
-                        .map_err(|_| DBError::DecodingError)?;
-
-                    // transform to emails
-                    bincode::deserialize(&encrypted_blob).map_err(|_| DBError::DecodingError)
-                } else {
-                    Err(DBError::IncorrectFileFormat)
+            let mut buffer = &content[file_header.size..];
+
+            // - unique nonce 12 bytes
+            let mut nonce: EncryptionNonce = Default::default();
+            buffer.read_exact(&mut nonce).map_err(DBError::IOError)?;
+
+            // - tag 16 bytes
+            let mut tag: EncryptionTag = Default::default();
+            buffer.read_exact(&mut tag).map_err(DBError::IOError)?;
+
+            // - total encrypted block bytes length (8 bytes)
+            let block_size: u64 =
+                bincode::deserialize_from(&mut buffer).map_err(|_| DBError::DecodingError)?;
+            let block_size =
+                usize::try_from(block_size).expect("File size is too big for that platform");
+
+            // - encrypted block (see the size above)
+            let mut encrypted_blob: Vec<u8> = buffer.to_vec();
+            if encrypted_blob.len() == block_size {
+                // decrypt the blob
+                decrypt_in_place(
+                    key,
+                    &nonce,
+                    &file_header.associated_data,
+                    &mut encrypted_blob,
+                    &tag,
+                )
+                .map_err(|_| DBError::DecodingError)?;
+
+                // transform to emails, old v1 databases have no JMAP state
+                match bincode::deserialize::<(Vec<MaskedEmail>, Option<String>)>(&encrypted_blob) {
+                    Ok((emails, jmap_state)) => Ok(DatabaseContent { emails, jmap_state }),
+                    Err(_) if file_header.format_version == 1 => {
+                        bincode::deserialize(&encrypted_blob)
+                            .map(|emails| DatabaseContent {
+                                emails,
+                                jmap_state: None,
+                            })
+                            .map_err(|_| DBError::DecodingError)
+                    }
+                    Err(_) => Err(DBError::DecodingError),
                 }
             } else {
-                Err(DBError::DecodingError)
+                Err(DBError::IncorrectFileFormat)
             }
         } else {
-            Ok(Vec::new())
+            Ok(DatabaseContent::default())
         }
     }
 
     /// Update the database and store the new email list. It generates the database and encrypts all emails.
+    /// The new file is always written in the latest format next to the database and then atomically renamed
+    /// over it, the previous version is kept as a backup.
     ///
     /// # Arguments
     ///
     /// * `emails` - masked emails
+    /// * `jmap_state` - JMAP state the emails were synchronized at
     /// * `key` - AES encryption key
     pub fn store(
         &self,
-        emails: &Vec<crate::model::masked_email::MaskedEmail>,
+        emails: &Vec<MaskedEmail>,
+        jmap_state: Option<&str>,
         aes: &crate::secrets::AesKeyValue,
     ) -> Result<()> {
         use std::io::Write;
@@ -198,38 +470,44 @@ impl Database {
             std::fs::create_dir_all(root).map_err(DBError::IOError)?;
         }
 
+        let temp_path = self
+            .path
+            .with_extension(format!("enc.{TEMP_FILE_EXTENSION}"));
         let file = fs::OpenOptions::new()
             .write(true)
             .create(true)
-            .open(self.path.as_path())
+            .truncate(true)
+            .open(temp_path.as_path())
             .map_err(DBError::IOError)?;
 
         let mut buffer = BufWriter::new(file);
 
-        let file_header = FileHeader {
-            file_signature: FILE_SIGNATURE,
+        let file_header = FileHeaderV3 {
+            file_signature: FILE_SIGNATURE_V3,
             nonce: self.key_derivation_salt,
             last_updated: Utc::now(),
             records_count: u32::try_from(emails.len()).expect("Arrays is too big"),
+            kdf_params: self.kdf_params,
+            schema_version: SCHEMA_VERSION,
+            key_source: self.key_source.clone(),
         };
 
         // serialize header
         bincode::serialize_into(&mut buffer, &file_header).expect("Error is not expected");
 
         // encode emails list
-        let mut content_buffer = bincode::serialize(&emails).map_err(|_| DBError::EncodingError)?;
+        let mut content_buffer =
+            bincode::serialize(&(emails, jmap_state)).map_err(|_| DBError::EncodingError)?;
 
-        // encrypt that block
-        let associated_data =
-            bincode::serialize(&(file_header.last_updated, file_header.records_count))
-                .expect("No error expected");
+        // encrypt that block, the whole header is authenticated
+        let associated_data = bincode::serialize(&file_header).expect("No error expected");
         let (tag, nonce) = encrypt_in_place(aes, &associated_data, &mut content_buffer)
             .map_err(|_| DBError::EncodingError)?;
 
         // serialize nonce
-        buffer.write(&nonce).map_err(DBError::IOError)?;
+        buffer.write_all(&nonce).map_err(DBError::IOError)?;
         // serialize tag
-        buffer.write(tag.as_slice()).map_err(DBError::IOError)?;
+        buffer.write_all(tag.as_slice()).map_err(DBError::IOError)?;
 
         // serialize binary length
         let blob_size: u64 = content_buffer
@@ -239,10 +517,94 @@ impl Database {
         bincode::serialize_into(&mut buffer, &blob_size).map_err(|_| DBError::EncodingError)?;
 
         // serialize binary
-        buffer.write(&content_buffer).map_err(DBError::IOError)?;
+        buffer
+            .write_all(&content_buffer)
+            .map_err(DBError::IOError)?;
+
+        // flush everything to the disk before the file replaces the database
+        let file = buffer
+            .into_inner()
+            .map_err(|e| DBError::IOError(e.into_error()))?;
+        file.sync_all().map_err(DBError::IOError)?;
+        drop(file);
+
+        self.replace_with(&temp_path)
+    }
+
+    /// Atomically replace the database with the new file and keep the previous version as a backup.
+    fn replace_with(&self, new_file: &std::path::Path) -> Result<()> {
+        if self.path.exists() && self.backups_count > 0 {
+            self.rotate_backups()?;
+
+            // hard links are not supported by every file system
+            let backup_path = self.backup_path(1);
+            if let Err(e) = fs::hard_link(&self.path, &backup_path) {
+                log::debug!("Backup cannot be linked, it will be copied: {e}");
+                fs::copy(&self.path, &backup_path).map_err(DBError::IOError)?;
+            }
+        }
+
+        fs::rename(new_file, &self.path).map_err(DBError::IOError)?;
+
+        // persist the rename itself
+        #[cfg(unix)]
+        if let Some(root) = self.path.parent() {
+            fs::File::open(root)
+                .and_then(|dir| dir.sync_all())
+                .map_err(DBError::IOError)?;
+        }
+
+        Ok(())
+    }
+
+    /// Shift all backups by one and remove the ones that exceed the backups count.
+    /// The first position is free after that call.
+    fn rotate_backups(&self) -> Result<()> {
+        let mut indexes = self.backup_indexes()?;
+        indexes.sort_unstable_by(|a, b| b.cmp(a));
+
+        for index in indexes {
+            let backup_path = self.backup_path(index);
+            if index >= self.backups_count {
+                fs::remove_file(&backup_path).map_err(DBError::IOError)?;
+            } else {
+                fs::rename(&backup_path, self.backup_path(index + 1)).map_err(DBError::IOError)?;
+            }
+        }
 
         Ok(())
     }
+
+    /// Indexes of all existing backups (in any order).
+    fn backup_indexes(&self) -> Result<Vec<usize>> {
+        let prefix = format!("{DATABASE_FILE_NAME}.{BACKUP_FILE_EXTENSION}.");
+        let root = match self.path.parent() {
+            Some(root) if root.exists() => root,
+            _ => return Ok(Vec::new()),
+        };
+
+        let mut indexes = Vec::new();
+        for entry in fs::read_dir(root).map_err(DBError::IOError)? {
+            let entry = entry.map_err(DBError::IOError)?;
+            if let Some(index) = entry
+                .file_name()
+                .to_str()
+                .and_then(|name| name.strip_prefix(&prefix))
+                .and_then(|index| index.parse::<usize>().ok())
+            {
+                indexes.push(index);
+            }
+        }
+
+        Ok(indexes)
+    }
+
+    /// Path of the backup with the index, 1 is the most recent version.
+    fn backup_path(&self, index: usize) -> PathBuf {
+        self.path.with_file_name(format!(
+            "{DATABASE_FILE_NAME}.{BACKUP_FILE_EXTENSION}.{index}"
+        ))
+    }
 }
 
 #[cfg(test)]
@@ -252,15 +614,28 @@ mod test {
     use chrono::Utc;
 
     use crate::{
-        db::disk::{FileHeader, FILE_SIGNATURE},
+        db::disk::{FileHeaderV1, FILE_SIGNATURE_V1},
         model::masked_email::{MaskedEmail, MaskedEmailState},
         secrets::{
-            encryption::{generate_key, generate_new_salt},
+            encryption::{encrypt_in_place, generate_key, generate_new_salt, KdfParams},
             PasswordValue,
         },
     };
 
-    use super::{DBError, Database, DATABASE_FILE_NAME};
+    use super::{DBError, Database, DATABASE_FILE_NAME, FORMAT_VERSION, SCHEMA_VERSION};
+
+    fn sample_email() -> MaskedEmail {
+        MaskedEmail {
+            internal_id: "id1".to_owned(),
+            email: "example@example.com".to_owned(),
+            description: None,
+            web_site: None,
+            integration_url: None,
+            state: MaskedEmailState::Active,
+            created_at: Default::default(),
+            last_message_at: None,
+        }
+    }
 
     #[test]
     fn database_not_exists() {
@@ -298,8 +673,8 @@ mod test {
         let mut tmp_file: PathBuf = PathBuf::from(tmp_dir.path());
         tmp_file.push(DATABASE_FILE_NAME);
 
-        let file_header = FileHeader {
-            file_signature: FILE_SIGNATURE,
+        let file_header = FileHeaderV1 {
+            file_signature: FILE_SIGNATURE_V1,
             nonce: generate_new_salt(),
             last_updated: Utc::now(),
             records_count: 100u32,
@@ -313,6 +688,27 @@ mod test {
         assert_eq!(res.key_derivation_salt, file_header.nonce);
         assert_eq!(res.last_update, file_header.last_updated);
         assert_eq!(res.records_count, file_header.records_count);
+        assert_eq!(res.format_version, 1);
+        assert_eq!(res.kdf_params, KdfParams::default());
+    }
+
+    #[test]
+    fn unsupported_format_version() {
+        // make new tmp directory
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let mut tmp_file: PathBuf = PathBuf::from(tmp_dir.path());
+        tmp_file.push(DATABASE_FILE_NAME);
+
+        let file_header = FileHeaderV1 {
+            file_signature: [b'M', b'E', b'F', 9u8],
+            nonce: generate_new_salt(),
+            last_updated: Utc::now(),
+            records_count: 100u32,
+        };
+        fs::write(tmp_file, bincode::serialize(&file_header).unwrap()).expect("IO Error");
+
+        let res = Database::init(tmp_dir.path());
+        assert!(matches!(res, Err(DBError::UnsupportedFormatVersion(9))));
     }
 
     #[test]
@@ -342,11 +738,13 @@ mod test {
                 value: "weak_password".to_owned(),
             },
             &db1.key_derivation_salt,
+            &KdfParams::default(),
         )
         .expect("AES generation failed");
 
         // save database
-        db1.store(&records, &key).expect("Serialization failed");
+        db1.store(&records, None, &key)
+            .expect("Serialization failed");
 
         // try to read database
         let db2 = Database::init(tmp_dir.path()).expect("Failed to open the file");
@@ -363,4 +761,307 @@ mod test {
         // validate everything has been properly decrypted
         assert_eq!(res, records);
     }
+
+    #[test]
+    fn save_and_reload_jmap_state() {
+        // make new tmp directory
+        let tmp_dir = tempfile::tempdir().unwrap();
+
+        let db1 = Database::new(tmp_dir.path(), generate_new_salt());
+        let records = vec![sample_email()];
+        let key = generate_key(
+            &PasswordValue::from("weak_password".to_owned()),
+            &db1.key_derivation_salt,
+            &KdfParams::default(),
+        )
+        .expect("AES generation failed");
+
+        db1.store(&records, Some("state-1"), &key)
+            .expect("Serialization failed");
+
+        let db2 = Database::init(tmp_dir.path()).expect("Failed to open the file");
+        let content = db2.load_content(&key).expect("Decryption failed");
+        assert_eq!(content.emails, records);
+        assert_eq!(content.jmap_state.as_deref(), Some("state-1"));
+    }
+
+    #[test]
+    fn empty_database_is_decrypted() {
+        let tmp_dir = tempfile::tempdir().unwrap();
+
+        let db1 = Database::new(tmp_dir.path(), generate_new_salt());
+        let key = |password: &str| {
+            generate_key(
+                &PasswordValue::from(password.to_owned()),
+                &db1.key_derivation_salt,
+                &KdfParams::default(),
+            )
+            .expect("AES generation failed")
+        };
+        db1.store(&vec![], Some("state-1"), &key("weak_password"))
+            .expect("Serialization failed");
+
+        // the key is checked even without records
+        let db2 = Database::init(tmp_dir.path()).expect("Failed to open the file");
+        assert!(db2.load_content(&key("another_password")).is_err());
+        let content = db2
+            .load_content(&key("weak_password"))
+            .expect("Decryption failed");
+        assert!(content.emails.is_empty());
+        assert_eq!(content.jmap_state.as_deref(), Some("state-1"));
+    }
+
+    #[test]
+    fn save_and_reload_kdf_params() {
+        // make new tmp directory
+        let tmp_dir = tempfile::tempdir().unwrap();
+
+        let kdf_params = KdfParams {
+            memory_cost: 32 * 1024,
+            iterations: 3,
+            parallelism: 2,
+            ..KdfParams::default()
+        };
+        let db1 = Database::new(tmp_dir.path(), generate_new_salt()).with_kdf_params(kdf_params);
+        let key = generate_key(
+            &PasswordValue::from("weak_password".to_owned()),
+            &db1.key_derivation_salt,
+            &db1.kdf_params,
+        )
+        .expect("AES generation failed");
+        db1.store(&vec![sample_email()], None, &key)
+            .expect("Serialization failed");
+
+        // the key is derived again with the stored params
+        let db2 = Database::init(tmp_dir.path()).expect("Failed to open the file");
+        assert_eq!(db2.kdf_params, kdf_params);
+        let key = generate_key(
+            &PasswordValue::from("weak_password".to_owned()),
+            &db2.key_derivation_salt,
+            &db2.kdf_params,
+        )
+        .expect("AES generation failed");
+        let content = db2.load_content(&key).expect("Decryption failed");
+        assert_eq!(content.emails, vec![sample_email()]);
+    }
+
+    #[test]
+    fn load_database_without_jmap_state() {
+        use std::io::Write;
+
+        // make new tmp directory
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let mut tmp_file: PathBuf = PathBuf::from(tmp_dir.path());
+        tmp_file.push(DATABASE_FILE_NAME);
+
+        let records = vec![sample_email()];
+        let file_header = FileHeaderV1 {
+            file_signature: FILE_SIGNATURE_V1,
+            nonce: generate_new_salt(),
+            last_updated: Utc::now(),
+            records_count: 1u32,
+        };
+        let key = generate_key(
+            &PasswordValue::from("weak_password".to_owned()),
+            &file_header.nonce,
+            &KdfParams::default(),
+        )
+        .expect("AES generation failed");
+
+        // write the body with the emails list only
+        let mut content = bincode::serialize(&records).unwrap();
+        let associated_data =
+            bincode::serialize(&(file_header.last_updated, file_header.records_count)).unwrap();
+        let (tag, nonce) = encrypt_in_place(&key, &associated_data, &mut content).unwrap();
+
+        let mut file = fs::File::create(tmp_file).unwrap();
+        bincode::serialize_into(&mut file, &file_header).unwrap();
+        file.write_all(&nonce).unwrap();
+        file.write_all(tag.as_slice()).unwrap();
+        bincode::serialize_into(&mut file, &(content.len() as u64)).unwrap();
+        file.write_all(&content).unwrap();
+
+        let db = Database::init(tmp_dir.path()).expect("Failed to open the file");
+        let content = db.load_content(&key).expect("Decryption failed");
+        assert_eq!(content.emails, records);
+        assert_eq!(content.jmap_state, None);
+        assert_eq!(db.format_version, 1);
+
+        // the next update migrates the file to the latest format
+        db.store(&content.emails, Some("state-1"), &key)
+            .expect("Serialization failed");
+        let migrated = Database::init(tmp_dir.path()).expect("Failed to open the file");
+        assert_eq!(migrated.format_version, FORMAT_VERSION);
+        assert_eq!(migrated.schema_version, SCHEMA_VERSION);
+        assert_eq!(migrated.kdf_params, KdfParams::default());
+        assert_eq!(migrated.key_derivation_salt, file_header.nonce);
+
+        let content = migrated.load_content(&key).expect("Decryption failed");
+        assert_eq!(content.emails, records);
+        assert_eq!(content.jmap_state.as_deref(), Some("state-1"));
+    }
+
+    #[test]
+    fn store_shorter_database_keeps_backup() {
+        // make new tmp directory
+        let tmp_dir = tempfile::tempdir().unwrap();
+
+        let db = Database::new(tmp_dir.path(), generate_new_salt());
+        let key = generate_key(
+            &PasswordValue::from("weak_password".to_owned()),
+            &db.key_derivation_salt,
+            &KdfParams::default(),
+        )
+        .expect("AES generation failed");
+
+        let mut long_list = vec![sample_email(); 10];
+        long_list
+            .iter_mut()
+            .enumerate()
+            .for_each(|(i, e)| e.internal_id = format!("id{i}"));
+        db.store(&long_list, Some("state-1"), &key)
+            .expect("Serialization failed");
+
+        let short_list = vec![sample_email()];
+        db.store(&short_list, Some("state-2"), &key)
+            .expect("Serialization failed");
+
+        // the new version has no trailing data of the previous one
+        let reloaded = Database::init(tmp_dir.path()).expect("Failed to open the file");
+        let content = reloaded.load_content(&key).expect("Decryption failed");
+        assert_eq!(content.emails, short_list);
+        assert_eq!(content.jmap_state.as_deref(), Some("state-2"));
+
+        // the previous version is kept as a backup
+        let mut backup_path = PathBuf::from(tmp_dir.path());
+        backup_path.push(format!("{DATABASE_FILE_NAME}.bak.1"));
+        let mut file_path = PathBuf::from(tmp_dir.path());
+        file_path.push(DATABASE_FILE_NAME);
+        assert!(
+            fs::metadata(&backup_path).unwrap().len() > fs::metadata(&file_path).unwrap().len()
+        );
+
+        let mut temp_path = PathBuf::from(tmp_dir.path());
+        temp_path.push(format!("{DATABASE_FILE_NAME}.tmp"));
+        assert!(!temp_path.exists());
+    }
+
+    #[test]
+    fn rotate_and_restore_backups() {
+        // make new tmp directory
+        let tmp_dir = tempfile::tempdir().unwrap();
+
+        let db = Database::new(tmp_dir.path(), generate_new_salt()).with_backups(2);
+        let key = generate_key(
+            &PasswordValue::from("weak_password".to_owned()),
+            &db.key_derivation_salt,
+            &KdfParams::default(),
+        )
+        .expect("AES generation failed");
+
+        // 4 versions: the current one and 2 backups are kept
+        for version in 1..=4 {
+            db.store(
+                &vec![sample_email()],
+                Some(&format!("state-{version}")),
+                &key,
+            )
+            .expect("Serialization failed");
+        }
+
+        let backups = db.backups().expect("Backups are not listed");
+        assert_eq!(
+            backups.iter().map(|b| b.index).collect::<Vec<usize>>(),
+            vec![1, 2]
+        );
+        assert!(backups
+            .iter()
+            .all(|b| b.snapshot.as_ref().is_ok_and(|s| s.records_count == 1)));
+
+        // restore the oldest backup
+        db.restore(2, &key).expect("Backup is not restored");
+        let content = Database::init(tmp_dir.path())
+            .unwrap()
+            .load_content(&key)
+            .expect("Decryption failed");
+        assert_eq!(content.jmap_state.as_deref(), Some("state-2"));
+
+        // the replaced version becomes the most recent backup
+        let backup = Database::init(tmp_dir.path())
+            .unwrap()
+            .backups()
+            .unwrap()
+            .remove(0);
+        let previous = backup.snapshot.unwrap().load_content(&key).unwrap();
+        assert_eq!(previous.jmap_state.as_deref(), Some("state-4"));
+
+        // the backup header promises more emails than the file contains
+        let file_header = FileHeaderV1 {
+            file_signature: FILE_SIGNATURE_V1,
+            nonce: db.key_derivation_salt,
+            last_updated: Utc::now(),
+            records_count: 2u32,
+        };
+        let mut content = bincode::serialize(&vec![sample_email()]).unwrap();
+        let associated_data =
+            bincode::serialize(&(file_header.last_updated, file_header.records_count)).unwrap();
+        let (tag, nonce) = encrypt_in_place(&key, &associated_data, &mut content).unwrap();
+        let mut file = bincode::serialize(&file_header).unwrap();
+        file.extend_from_slice(&nonce);
+        file.extend_from_slice(tag.as_slice());
+        file.extend(bincode::serialize(&(content.len() as u64)).unwrap());
+        file.extend(content);
+        fs::write(db.backup_path(2), file).unwrap();
+
+        assert!(matches!(
+            db.restore(2, &key),
+            Err(DBError::RecordsCountMismatch {
+                expected: 2,
+                found: 1
+            })
+        ));
+        let current = Database::init(tmp_dir.path())
+            .unwrap()
+            .load_content(&key)
+            .unwrap();
+        assert_eq!(current.jmap_state.as_deref(), Some("state-2"));
+    }
+
+    #[test]
+    fn restore_rejects_backup_with_another_key() {
+        // make new tmp directory
+        let tmp_dir = tempfile::tempdir().unwrap();
+
+        let db = Database::new(tmp_dir.path(), generate_new_salt());
+        let key = generate_key(
+            &PasswordValue::from("weak_password".to_owned()),
+            &db.key_derivation_salt,
+            &KdfParams::default(),
+        )
+        .expect("AES generation failed");
+        let another_key = generate_key(
+            &PasswordValue::from("another_password".to_owned()),
+            &db.key_derivation_salt,
+            &KdfParams::default(),
+        )
+        .expect("AES generation failed");
+
+        db.store(&vec![sample_email()], Some("state-1"), &another_key)
+            .expect("Serialization failed");
+        db.store(&vec![sample_email()], Some("state-2"), &key)
+            .expect("Serialization failed");
+
+        assert!(matches!(db.restore(1, &key), Err(DBError::DecodingError)));
+        assert!(matches!(
+            db.restore(5, &key),
+            Err(DBError::BackupNotFound(5))
+        ));
+
+        // the database is not changed
+        let content = Database::init(tmp_dir.path())
+            .unwrap()
+            .load_content(&key)
+            .expect("Decryption failed");
+        assert_eq!(content.jmap_state.as_deref(), Some("state-2"));
+    }
 }
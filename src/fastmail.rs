@@ -1,19 +1,23 @@
-use reqwest::{blocking::Client, header, StatusCode};
-use serde_json::json;
+use std::{fmt::Display, path::PathBuf};
+
+use chrono::Utc;
+use reqwest::{blocking::Client, header, Certificate, StatusCode};
+use serde_json::{json, Value};
 use thiserror::Error;
 
 use crate::{
     fastmail::json::{
-        method_response::{JMapMethodResponse, JMapResponse, MethodResponse},
+        method_response::{JMapMethodResponse, JMapResponse, MethodErrorJson, MethodResponse},
         session::SessionResponse,
     },
-    model::masked_email::MaskedEmail,
+    model::masked_email::{MaskedEmail, MaskedEmailState},
     secrets::PasswordValue,
 };
 
 mod json;
 
 const SESSION_API_URL: &str = "https://api.fastmail.com/jmap/session";
+const MASKED_EMAIL_CAPABILITY: &str = "https://www.fastmail.com/dev/maskedemail";
 
 #[derive(Debug, Error)]
 pub enum FastMailError {
@@ -21,6 +25,139 @@ pub enum FastMailError {
     RequestFailed(#[from] reqwest::Error),
     #[error("http request failed: [{0}]: {1}")]
     RequestErrorCode(StatusCode, String),
+    #[error("masked email was not created: {0}")]
+    NotCreated(String),
+    #[error("masked email was not updated: {0}")]
+    NotUpdated(String),
+    #[error("response does not contain the expected method result")]
+    UnexpectedResponse,
+    #[error("method call failed: {0}{}", .1.as_ref().map(|d| format!(" ({d})")).unwrap_or_default())]
+    MethodFailed(MethodError, Option<String>),
+    #[error("certificate cannot be loaded: {0}")]
+    Certificate(String),
+}
+
+/// JMAP method level errors (RFC 8620, section 3.6.2).
+#[derive(Debug, PartialEq)]
+pub enum MethodError {
+    AccountNotFound,
+    AccountNotSupportedByMethod,
+    AccountReadOnly,
+    Forbidden,
+    UnknownMethod,
+    InvalidArguments,
+    InvalidResultReference,
+    RequestTooLarge,
+    CannotCalculateChanges,
+    ServerUnavailable,
+    ServerFail,
+    ServerPartialFail,
+    Unknown(String),
+}
+
+impl From<&str> for MethodError {
+    fn from(value: &str) -> Self {
+        match value {
+            "accountNotFound" => MethodError::AccountNotFound,
+            "accountNotSupportedByMethod" => MethodError::AccountNotSupportedByMethod,
+            "accountReadOnly" => MethodError::AccountReadOnly,
+            "forbidden" => MethodError::Forbidden,
+            "unknownMethod" => MethodError::UnknownMethod,
+            "invalidArguments" => MethodError::InvalidArguments,
+            "invalidResultReference" => MethodError::InvalidResultReference,
+            "requestTooLarge" => MethodError::RequestTooLarge,
+            "cannotCalculateChanges" => MethodError::CannotCalculateChanges,
+            "serverUnavailable" => MethodError::ServerUnavailable,
+            "serverFail" => MethodError::ServerFail,
+            "serverPartialFail" => MethodError::ServerPartialFail,
+            other => MethodError::Unknown(other.to_owned()),
+        }
+    }
+}
+
+impl Display for MethodError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            MethodError::AccountNotFound => write!(f, "account is not found"),
+            MethodError::AccountNotSupportedByMethod => {
+                write!(f, "account does not support masked emails")
+            }
+            MethodError::AccountReadOnly => write!(f, "account is read-only"),
+            MethodError::Forbidden => write!(f, "access is forbidden, check the token scope"),
+            MethodError::UnknownMethod => write!(f, "method is not supported by the server"),
+            MethodError::InvalidArguments => write!(f, "invalid arguments"),
+            MethodError::InvalidResultReference => write!(f, "invalid result reference"),
+            MethodError::RequestTooLarge => write!(f, "request is too large"),
+            MethodError::CannotCalculateChanges => write!(f, "changes cannot be calculated"),
+            MethodError::ServerUnavailable => write!(f, "server is unavailable"),
+            MethodError::ServerFail => write!(f, "server failed"),
+            MethodError::ServerPartialFail => write!(f, "server partially failed"),
+            MethodError::Unknown(error_type) => write!(f, "{error_type}"),
+        }
+    }
+}
+
+impl From<MethodErrorJson> for FastMailError {
+    fn from(value: MethodErrorJson) -> Self {
+        FastMailError::MethodFailed(value.error_type.as_str().into(), value.description)
+    }
+}
+
+/// All masked emails and the JMAP state string they were loaded at.
+pub struct MaskedEmails {
+    pub emails: Vec<MaskedEmail>,
+    pub state: Option<String>,
+}
+
+/// Masked emails changed since the previous state.
+pub struct MaskedEmailChanges {
+    /// Created and updated emails
+    pub changed: Vec<MaskedEmail>,
+    /// IDs of the removed emails
+    pub destroyed: Vec<String>,
+    pub new_state: String,
+}
+
+/// Result of the masked emails update. Every requested ID is either updated or rejected.
+pub struct UpdateResult {
+    pub updated: Vec<String>,
+    pub not_updated: Vec<(String, String)>,
+}
+
+/// Changed properties of the masked email. `None` values are not changed, empty strings remove the value.
+pub struct MaskedEmailUpdate {
+    pub for_domain: Option<String>,
+    pub description: Option<String>,
+    pub url: Option<String>,
+}
+
+/// Properties of the masked email that is going to be created.
+pub struct NewMaskedEmail {
+    pub for_domain: Option<String>,
+    pub description: Option<String>,
+    pub url: Option<String>,
+    pub email_prefix: Option<String>,
+    pub state: MaskedEmailState,
+}
+
+/// Connection parameters of the JMAP server.
+pub struct ConnectionSettings {
+    /// JMAP session endpoint
+    pub session_url: String,
+    /// Disable TLS certificate verification (only for local test servers)
+    pub accept_invalid_certs: bool,
+    /// Additional root certificate in PEM format
+    pub ca_certificate: Option<PathBuf>,
+}
+
+impl Default for ConnectionSettings {
+    fn default() -> Self {
+        Self {
+            session_url: SESSION_API_URL.to_owned(),
+            accept_invalid_certs: false,
+            ca_certificate: None,
+        }
+    }
 }
 
 pub type Result<A> = std::result::Result<A, FastMailError>;
@@ -35,9 +172,10 @@ pub struct FastMailClient {
 impl FastMailClient {
     /// Create a new fast mail session. That calls fastmail session api
     /// to derive the right api sql and validate user token.
-    pub fn new(token: PasswordValue) -> Result<Self> {
-        let client = FastMailClient::make_client();
-        let req = client.get(SESSION_API_URL).bearer_auth(&token.value);
+    pub fn new(token: PasswordValue, settings: &ConnectionSettings) -> Result<Self> {
+        let client = FastMailClient::make_client(settings)?;
+        log::debug!("Open the session: [{}]", settings.session_url);
+        let req = client.get(&settings.session_url).bearer_auth(&token.value);
 
         let resp = req.send().map_err(FastMailError::from)?;
 
@@ -62,54 +200,262 @@ impl FastMailClient {
     ///
     /// # Returns
     ///
-    /// List of all current emails, including disabled.
-    pub fn load_emails(&self) -> Result<Vec<MaskedEmail>> {
+    /// List of all current emails, including disabled, and the current JMAP state.
+    pub fn load_emails(&self) -> Result<MaskedEmails> {
+        log::info!("Load emails for the user: [{}]", self.account);
+        self.get_emails(None)
+    }
+
+    /// Load Masked Emails changed since the state.
+    ///
+    /// # Arguments
+    ///
+    /// * `since_state` - JMAP state returned by the previous call
+    ///
+    /// # Returns
+    ///
+    /// Created, updated and destroyed emails. [MethodError::CannotCalculateChanges] is returned
+    /// when the state is too old and the full list has to be loaded.
+    pub fn load_changes(&self, since_state: &str) -> Result<MaskedEmailChanges> {
         let user_id: &str = self.account.as_str();
         let query_id = "a";
-        let body = json!({
-            "using": [ "https://www.fastmail.com/dev/maskedemail" ],
-            "methodCalls": [
-                ["MaskedEmail/get",
-                    { "accountId": user_id},
-                 query_id
-                ]
-            ]
-        });
 
-        log::info!("Load emails for the user: [{}]", user_id);
+        let mut changes = MaskedEmailChanges {
+            changed: Vec::new(),
+            destroyed: Vec::new(),
+            new_state: since_state.to_owned(),
+        };
 
-        let req = self
-            .client
-            .post(&self.url)
-            .bearer_auth(&self.token.value)
-            .header(header::CONTENT_TYPE, "application/json")
-            .json(&body);
+        log::info!(
+            "Load changes for the user: [{}] since [{}]",
+            user_id,
+            since_state
+        );
 
-        let resp = req.send().map_err(FastMailError::from)?;
+        loop {
+            let method_calls = json!([
+                ["MaskedEmail/changes",
+                    { "accountId": user_id, "sinceState": changes.new_state },
+                 query_id
+                ]
+            ]);
 
-        if resp.status() == StatusCode::OK {
-            let resp = resp.json::<JMapResponse>().map_err(FastMailError::from)?;
-            let emails = resp
+            let resp = self.call(method_calls)?;
+            let resp = resp
                 .method_responses
                 .into_iter()
-                .filter_map(|response| {
-                    if let JMapMethodResponse(_, MethodResponse::MaskedEmailGet(resp), _) = response
-                    {
-                        let res = resp.list.into_iter().map(|email| email.into());
-                        Some(res)
-                    } else {
-                        None
+                .find_map(|response| match response {
+                    JMapMethodResponse(_, MethodResponse::MaskedEmailChanges(resp), _) => {
+                        Some(Ok(resp))
                     }
+                    JMapMethodResponse(_, MethodResponse::Error(error), _) => {
+                        Some(Err(error.into()))
+                    }
+                    _ => None,
                 })
-                .flatten()
-                .collect::<Vec<MaskedEmail>>();
+                .unwrap_or(Err(FastMailError::UnexpectedResponse))?;
 
-            Ok(emails)
-        } else {
-            let error_code = resp.status();
-            let resp = resp.text().map_err(FastMailError::from)?;
-            Err(FastMailError::RequestErrorCode(error_code, resp))
+            log::debug!(
+                "Changes: {} created, {} updated, {} destroyed",
+                resp.created.len(),
+                resp.updated.len(),
+                resp.destroyed.len()
+            );
+
+            let ids: Vec<String> = resp.created.into_iter().chain(resp.updated).collect();
+            if !ids.is_empty() {
+                let loaded = self.get_emails(Some(&ids))?;
+                // the email could be changed again, keep only the latest version
+                changes
+                    .changed
+                    .retain(|e| !loaded.emails.iter().any(|l| l.internal_id == e.internal_id));
+                changes.changed.extend(loaded.emails);
+            }
+
+            changes
+                .changed
+                .retain(|e| !resp.destroyed.contains(&e.internal_id));
+            changes.destroyed.extend(resp.destroyed);
+            changes.new_state = resp.new_state;
+
+            if !resp.has_more_changes {
+                break;
+            }
+        }
+
+        Ok(changes)
+    }
+
+    /// Create a new Masked Email.
+    ///
+    /// # Arguments
+    ///
+    /// * `new_email` - properties of the new masked email
+    ///
+    /// # Returns
+    ///
+    /// Created masked email with the address generated by the server.
+    pub fn create_email(&self, new_email: &NewMaskedEmail) -> Result<MaskedEmail> {
+        let user_id: &str = self.account.as_str();
+        let query_id = "a";
+        let create_id = "new";
+
+        let mut properties = serde_json::Map::new();
+        properties.insert("state".to_owned(), json!(new_email.state.to_string()));
+        if let Some(for_domain) = &new_email.for_domain {
+            properties.insert("forDomain".to_owned(), json!(for_domain));
         }
+        if let Some(description) = &new_email.description {
+            properties.insert("description".to_owned(), json!(description));
+        }
+        if let Some(url) = &new_email.url {
+            properties.insert("url".to_owned(), json!(url));
+        }
+        if let Some(email_prefix) = &new_email.email_prefix {
+            properties.insert("emailPrefix".to_owned(), json!(email_prefix));
+        }
+
+        let method_calls = json!([
+            ["MaskedEmail/set",
+                { "accountId": user_id, "create": { create_id: properties } },
+             query_id
+            ]
+        ]);
+
+        log::info!("Create a new email for the user: [{}]", user_id);
+
+        let resp = self.call(method_calls)?;
+        for response in resp.method_responses {
+            if let JMapMethodResponse(_, MethodResponse::MaskedEmailSet(mut resp), _) = response {
+                if let Some(created) = resp.created.as_mut().and_then(|c| c.remove(create_id)) {
+                    return Ok(MaskedEmail {
+                        internal_id: created.id,
+                        email: created.email,
+                        description: new_email.description.clone(),
+                        web_site: new_email.for_domain.clone(),
+                        integration_url: new_email.url.clone(),
+                        state: created.state.map(|s| s.into()).unwrap_or(new_email.state),
+                        created_at: created.created_at.unwrap_or_else(Utc::now),
+                        last_message_at: None,
+                    });
+                }
+
+                if let Some(error) = resp.not_created.as_mut().and_then(|c| c.remove(create_id)) {
+                    return Err(FastMailError::NotCreated(error.to_string()));
+                }
+            } else if let JMapMethodResponse(_, MethodResponse::Error(error), _) = response {
+                return Err(error.into());
+            }
+        }
+
+        Err(FastMailError::UnexpectedResponse)
+    }
+
+    /// Change the state of Masked Emails.
+    ///
+    /// # Arguments
+    ///
+    /// * `ids` - fastmail email IDs
+    /// * `state` - new state for all these emails
+    ///
+    /// # Returns
+    ///
+    /// IDs confirmed by the server and IDs rejected with the reason.
+    pub fn update_state(&self, ids: &[String], state: MaskedEmailState) -> Result<UpdateResult> {
+        let patches = ids
+            .iter()
+            .map(|id| (id.to_owned(), json!({ "state": state.to_string() })))
+            .collect::<serde_json::Map<String, Value>>();
+
+        log::info!("Change state of {} emails to [{}]", ids.len(), state);
+        self.update_emails(patches)
+    }
+
+    /// Update properties of the Masked Email.
+    ///
+    /// # Arguments
+    ///
+    /// * `id` - fastmail email ID
+    /// * `update` - changed properties, an empty string removes the value
+    ///
+    /// # Returns
+    ///
+    /// nothing in case the server confirmed the update
+    pub fn update_email(&self, id: &str, update: &MaskedEmailUpdate) -> Result<()> {
+        let mut properties = serde_json::Map::new();
+        if let Some(for_domain) = &update.for_domain {
+            properties.insert("forDomain".to_owned(), json!(for_domain));
+        }
+        if let Some(description) = &update.description {
+            properties.insert("description".to_owned(), json!(description));
+        }
+        if let Some(url) = &update.url {
+            let url = if url.is_empty() {
+                Value::Null
+            } else {
+                json!(url)
+            };
+            properties.insert("url".to_owned(), url);
+        }
+
+        let mut patches = serde_json::Map::new();
+        patches.insert(id.to_owned(), Value::Object(properties));
+
+        log::info!("Update the email: [{}]", id);
+        let result = self.update_emails(patches)?;
+
+        match result.not_updated.into_iter().next() {
+            Some((_, reason)) => Err(FastMailError::NotUpdated(reason)),
+            None if result.updated.iter().any(|u| u == id) => Ok(()),
+            None => Err(FastMailError::UnexpectedResponse),
+        }
+    }
+
+    /// Call MaskedEmail/set with the update patches.
+    ///
+    /// # Arguments
+    ///
+    /// * `patches` - map of fastmail email ID to the changed properties
+    ///
+    /// # Returns
+    ///
+    /// IDs confirmed by the server and IDs rejected with the reason.
+    fn update_emails(&self, patches: serde_json::Map<String, Value>) -> Result<UpdateResult> {
+        let user_id: &str = self.account.as_str();
+        let query_id = "a";
+        let method_calls = json!([
+            ["MaskedEmail/set",
+                { "accountId": user_id, "update": patches },
+             query_id
+            ]
+        ]);
+
+        let resp = self.call(method_calls)?;
+        for response in resp.method_responses {
+            if let JMapMethodResponse(_, MethodResponse::MaskedEmailSet(resp), _) = response {
+                let updated = resp
+                    .updated
+                    .map(|u| u.into_keys().collect())
+                    .unwrap_or_default();
+                let not_updated = resp
+                    .not_updated
+                    .map(|u| {
+                        u.into_iter()
+                            .map(|(id, error)| (id, error.to_string()))
+                            .collect()
+                    })
+                    .unwrap_or_default();
+
+                return Ok(UpdateResult {
+                    updated,
+                    not_updated,
+                });
+            } else if let JMapMethodResponse(_, MethodResponse::Error(error), _) = response {
+                return Err(error.into());
+            }
+        }
+
+        Err(FastMailError::UnexpectedResponse)
     }
 
     /// Load Masked Email.
@@ -139,8 +485,94 @@ impl FastMailClient {
     //     Ok(reloaded.expect("Reloading email failed"))
     // }
 
-    #[inline]
-    fn make_client() -> Client {
-        reqwest::blocking::Client::new()
+    /// Call MaskedEmail/get.
+    ///
+    /// # Arguments
+    ///
+    /// * `ids` - fastmail email IDs, all emails are loaded if that is empty
+    ///
+    /// # Returns
+    ///
+    /// Loaded emails and the current JMAP state.
+    fn get_emails(&self, ids: Option<&[String]>) -> Result<MaskedEmails> {
+        let user_id: &str = self.account.as_str();
+        let query_id = "a";
+        let method_calls = json!([
+            ["MaskedEmail/get",
+                { "accountId": user_id, "ids": ids },
+             query_id
+            ]
+        ]);
+
+        let resp = self.call(method_calls)?;
+        let mut loaded: Option<MaskedEmails> = None;
+
+        // any failed method makes the whole result invalid
+        for response in resp.method_responses {
+            match response {
+                JMapMethodResponse(_, MethodResponse::MaskedEmailGet(resp), _) => {
+                    let emails = resp.list.into_iter().map(|email| email.into()).collect();
+                    loaded = Some(MaskedEmails {
+                        emails,
+                        state: resp.state,
+                    });
+                }
+                JMapMethodResponse(_, MethodResponse::Error(error), _) => {
+                    log::error!("MaskedEmail/get failed: {:?}", error);
+                    return Err(error.into());
+                }
+                _ => (),
+            }
+        }
+
+        loaded.ok_or(FastMailError::UnexpectedResponse)
+    }
+
+    /// Send JMAP method calls to the api endpoint.
+    ///
+    /// # Arguments
+    ///
+    /// * `method_calls` - array of JMAP invocations
+    ///
+    /// # Returns
+    ///
+    /// Parsed JMAP response in case the server returned http 200.
+    fn call(&self, method_calls: Value) -> Result<JMapResponse> {
+        let body = json!({
+            "using": [ MASKED_EMAIL_CAPABILITY ],
+            "methodCalls": method_calls
+        });
+
+        let req = self
+            .client
+            .post(&self.url)
+            .bearer_auth(&self.token.value)
+            .header(header::CONTENT_TYPE, "application/json")
+            .json(&body);
+
+        let resp = req.send().map_err(FastMailError::from)?;
+
+        if resp.status() == StatusCode::OK {
+            resp.json::<JMapResponse>().map_err(FastMailError::from)
+        } else {
+            let error_code = resp.status();
+            let resp = resp.text().map_err(FastMailError::from)?;
+            Err(FastMailError::RequestErrorCode(error_code, resp))
+        }
+    }
+
+    fn make_client(settings: &ConnectionSettings) -> Result<Client> {
+        let mut builder =
+            Client::builder().danger_accept_invalid_certs(settings.accept_invalid_certs);
+
+        if let Some(path) = &settings.ca_certificate {
+            let pem = std::fs::read(path)
+                .map_err(|e| FastMailError::Certificate(format!("{}: {e}", path.display())))?;
+            let certificate = Certificate::from_pem(&pem)
+                .map_err(|e| FastMailError::Certificate(format!("{}: {e}", path.display())))?;
+            builder = builder.add_root_certificate(certificate);
+        }
+
+        builder.build().map_err(FastMailError::from)
     }
 }
@@ -1,3 +1,5 @@
+pub mod masked_email_changes;
 pub mod masked_email_get;
+pub mod masked_email_set;
 pub mod method_response;
 pub mod session;
@@ -0,0 +1,16 @@
+use serde::Deserialize;
+
+#[derive(Deserialize)]
+pub struct MaskedEmailChanges {
+    // part of the response, the account is already known from the session
+    #[allow(dead_code)]
+    #[serde(rename(deserialize = "accountId"))]
+    pub account_id: String,
+    #[serde(rename(deserialize = "newState"))]
+    pub new_state: String,
+    #[serde(rename(deserialize = "hasMoreChanges"))]
+    pub has_more_changes: bool,
+    pub created: Vec<String>,
+    pub updated: Vec<String>,
+    pub destroyed: Vec<String>,
+}
@@ -7,15 +7,10 @@ use crate::model::masked_email::{MaskedEmail, MaskedEmailState};
 pub struct MaskedEmailGet {
     #[serde(rename(deserialize = "accountId"))]
     pub account_id: String,
+    pub state: Option<String>,
     pub list: Vec<MaskedEmailJson>,
 }
 
-#[derive(Deserialize)]
-pub struct MaskedEmailSet {
-    #[serde(rename(deserialize = "accountId"))]
-    pub account_id: String,
-    pub create: String,
-}
 #[derive(Deserialize, Debug)]
 pub enum MaskedEmailStateJson {
     #[serde(rename(deserialize = "pending"))]
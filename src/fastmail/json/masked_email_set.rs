@@ -0,0 +1,48 @@
+use std::collections::HashMap;
+
+use chrono::{DateTime, Utc};
+use serde::Deserialize;
+use serde_json::Value;
+
+use super::masked_email_get::MaskedEmailStateJson;
+
+#[derive(Deserialize)]
+pub struct MaskedEmailSet {
+    // required to tell the response from the method error, the account is already known from the session
+    #[allow(dead_code)]
+    #[serde(rename(deserialize = "accountId"))]
+    pub account_id: String,
+    pub created: Option<HashMap<String, MaskedEmailCreatedJson>>,
+    #[serde(rename(deserialize = "notCreated"))]
+    pub not_created: Option<HashMap<String, SetErrorJson>>,
+    pub updated: Option<HashMap<String, Option<Value>>>,
+    #[serde(rename(deserialize = "notUpdated"))]
+    pub not_updated: Option<HashMap<String, SetErrorJson>>,
+}
+
+/// Server-set properties of the newly created masked email.
+/// Fields that were sent in the request are not always returned back.
+#[derive(Deserialize)]
+pub struct MaskedEmailCreatedJson {
+    pub id: String,
+    pub email: String,
+    pub state: Option<MaskedEmailStateJson>,
+    #[serde(rename(deserialize = "createdAt"))]
+    pub created_at: Option<DateTime<Utc>>,
+}
+
+#[derive(Deserialize, Debug)]
+pub struct SetErrorJson {
+    #[serde(rename(deserialize = "type"))]
+    pub error_type: String,
+    pub description: Option<String>,
+}
+
+impl std::fmt::Display for SetErrorJson {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match &self.description {
+            Some(description) => write!(f, "{} ({})", self.error_type, description),
+            None => write!(f, "{}", self.error_type),
+        }
+    }
+}
@@ -1,6 +1,9 @@
 use serde::Deserialize;
 
-use super::masked_email_get::{MaskedEmailGet, MaskedEmailSet};
+use super::{
+    masked_email_changes::MaskedEmailChanges, masked_email_get::MaskedEmailGet,
+    masked_email_set::MaskedEmailSet,
+};
 
 #[derive(Deserialize)]
 pub struct JMapResponse {
@@ -11,9 +14,71 @@ pub struct JMapResponse {
 #[derive(Deserialize)]
 pub struct JMapMethodResponse(pub String, pub MethodResponse, pub String);
 
+/// Arguments of the method response. The order matters: `MaskedEmailChanges` must be
+/// checked before `MaskedEmailSet` as the last one would accept changes response as well.
 #[derive(Deserialize)]
 #[serde(untagged)]
 pub enum MethodResponse {
     MaskedEmailGet(MaskedEmailGet),
+    MaskedEmailChanges(MaskedEmailChanges),
     MaskedEmailSet(MaskedEmailSet),
+    Error(MethodErrorJson),
+}
+
+/// Method level error, the response name is `error` in that case.
+#[derive(Deserialize, Debug)]
+pub struct MethodErrorJson {
+    #[serde(rename(deserialize = "type"))]
+    pub error_type: String,
+    pub description: Option<String>,
+}
+
+#[cfg(test)]
+mod test {
+    use super::{JMapMethodResponse, JMapResponse, MethodResponse};
+
+    #[test]
+    fn parse_method_error() {
+        let body = r#"{
+            "methodResponses": [
+                ["error", {"type": "forbidden", "description": "token scope"}, "a"],
+                ["MaskedEmail/get", {"accountId": "u1", "state": "10", "list": []}, "b"]
+            ]
+        }"#;
+
+        let resp: JMapResponse = serde_json::from_str(body).expect("Response is not parsed");
+        match &resp.method_responses[0] {
+            JMapMethodResponse(name, MethodResponse::Error(error), id) => {
+                assert_eq!(name, "error");
+                assert_eq!(error.error_type, "forbidden");
+                assert_eq!(error.description.as_deref(), Some("token scope"));
+                assert_eq!(id, "a");
+            }
+            _ => panic!("Error response is not recognized"),
+        }
+        match &resp.method_responses[1] {
+            JMapMethodResponse(_, MethodResponse::MaskedEmailGet(get), _) => {
+                assert_eq!(get.state.as_deref(), Some("10"));
+            }
+            _ => panic!("Get response is not recognized"),
+        }
+    }
+
+    #[test]
+    fn parse_changes_before_set() {
+        let body = r#"{
+            "methodResponses": [
+                ["MaskedEmail/changes", {
+                    "accountId": "u1", "oldState": "1", "newState": "2",
+                    "hasMoreChanges": false, "created": ["m1"], "updated": [], "destroyed": []
+                }, "a"]
+            ]
+        }"#;
+
+        let resp: JMapResponse = serde_json::from_str(body).expect("Response is not parsed");
+        assert!(matches!(
+            resp.method_responses[0],
+            JMapMethodResponse(_, MethodResponse::MaskedEmailChanges(_), _)
+        ));
+    }
 }
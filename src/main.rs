@@ -1,15 +1,38 @@
-use std::path::PathBuf;
+use std::{io::IsTerminal, path::PathBuf, process::ExitCode};
 
-use actions::{export_lua, refresh_db, show_emails};
+use chrono::{DateTime, Utc};
+use clap::ArgMatches;
+use regex::Regex;
+
+use actions::{
+    change_key_mode, create_email, edit_email, export_lua, find_emails, get_emails, list_backups,
+    list_emails,
+    list_emails::{EmailFilter, ListOptions},
+    migrate_db,
+    query::Query,
+    refresh_db, restore_backup, rotate_token, show_emails, update_state,
+};
 use config::{
-    run_args, userconfig::UserConfig, AppConfig, ConfigReader, COMMAND_EXPORT_LUA, COMMAND_INIT,
-    COMMAND_REFRESH_DB, COMMAND_SHOW_DB, COMMAND_UPDATE_PASSWORD,
+    run_args, userconfig::UserConfig, AppConfig, ConfigReader, SecretStorageKind, COMMAND_BACKUP,
+    COMMAND_BACKUP_LIST, COMMAND_BACKUP_RESTORE, COMMAND_CREATE, COMMAND_DELETE, COMMAND_DISABLE,
+    COMMAND_EDIT, COMMAND_ENABLE, COMMAND_EXPORT_LUA, COMMAND_GET, COMMAND_INIT, COMMAND_KEY_MODE,
+    COMMAND_LIST, COMMAND_MIGRATE_DB, COMMAND_PROFILES, COMMAND_PROFILES_LIST, COMMAND_REFRESH_DB,
+    COMMAND_ROTATE_TOKEN, COMMAND_SHOW_DB, COMMAND_UPDATE_PASSWORD, DEFAULT_PROFILE, TOKEN_ENV_VAR,
 };
-use secrets::keychain::KeyChain;
+use fastmail::{MaskedEmailUpdate, NewMaskedEmail};
+use model::masked_email::MaskedEmailState;
+use secrets::{registry::StorageRegistry, token_source::TokenSource};
 
 use crate::{
-    cli::{password_prompt, user_prompt},
-    secrets::{fastmail::SecureStorage, PasswordValue},
+    actions::KeyMode,
+    cli::{
+        confirm_prompt, new_password_prompt, password_prompt, user_prompt, user_prompt_with_initial,
+    },
+    secrets::{
+        encryption::KdfParams,
+        fastmail::{PasswordStorageError, SecureStorage},
+        PasswordValue,
+    },
 };
 
 mod actions;
@@ -19,74 +42,1248 @@ mod db;
 mod fastmail;
 mod model;
 mod secrets;
+#[cfg(test)]
+mod testing;
 
-fn main() {
+fn main() -> ExitCode {
     env_logger::init();
 
-    run_app::<KeyChain, UserConfig>();
+    let args = run_args().get_matches();
+
+    let config_storage =
+        UserConfig::default().with_profile(args.get_one::<String>("profile").cloned());
+    let config = config_storage.try_load().ok();
+
+    // the token from the command line or environment replaces the secret storage
+    let token = if let Some(path) = args.get_one::<PathBuf>("token-file") {
+        Some(TokenSource::read_file(path))
+    } else if let Some(fd) = args.get_one::<u32>("token-fd") {
+        Some(TokenSource::read_fd(*fd))
+    } else {
+        TokenSource::read_env(TOKEN_ENV_VAR).map(Ok)
+    };
+    let password_storage: Result<Box<dyn SecureStorage>, PasswordStorageError> = match token {
+        Some(Ok(token)) => {
+            if let Some(command) = stores_token(&args) {
+                eprintln!(
+                    "`{command}` stores the token in the secret storage, \
+                     please run it without {TOKEN_ENV_VAR}, --token-file or --token-fd"
+                );
+                return ExitCode::FAILURE;
+            }
+            Ok(Box::new(TokenSource::new(token)))
+        }
+        Some(Err(err)) => {
+            eprintln!("Token cannot be read: {err}");
+            return ExitCode::FAILURE;
+        }
+        None => {
+            // the flag replaces the backend from the config
+            let name = args
+                .get_one::<String>("secret-storage")
+                .cloned()
+                .or(config
+                    .as_ref()
+                    .and_then(|c| c.secret_storage)
+                    .map(|kind| kind.to_string()))
+                .unwrap_or_else(|| SecretStorageKind::default().to_string());
+            StorageRegistry::default().create(&name, config.as_ref())
+        }
+    };
+
+    ExitCode::from(run_app(args, &config_storage, password_storage.as_deref()))
 }
 
-fn run_app<PasswordStorage, ConfigStorage>()
-where
-    PasswordStorage: SecureStorage,
-    ConfigStorage: ConfigReader,
-{
+/// Find the command that writes the token to the secret storage, the read-only token source cannot be used
+/// for it.
+///
+/// # Returns
+///
+/// command name or empty if the command only reads the token
+fn stores_token(args: &ArgMatches) -> Option<&str> {
+    args.subcommand_name()
+        .filter(|name| [COMMAND_UPDATE_PASSWORD, COMMAND_ROTATE_TOKEN].contains(name))
+}
+
+/// Run the command.
+///
+/// # Arguments
+///
+/// * `config_storage` - where the configuration is kept
+/// * `password_storage` - where the token and AES key are kept, the error is shown if the command needs it
+///
+/// # Returns
+///
+/// process exit code, `get` reports that nothing is found with it
+fn run_app(
+    args: ArgMatches,
+    config_storage: &dyn ConfigReader,
+    password_storage: Result<&dyn SecureStorage, &PasswordStorageError>,
+) -> u8 {
     // load config
-    let config: Result<AppConfig, _> = ConfigStorage::try_load();
+    let config: Result<AppConfig, _> = config_storage.try_load();
 
-    let args = run_args().get_matches();
     match args.subcommand() {
-        Some((COMMAND_INIT, _)) => {
+        Some((COMMAND_INIT, args)) => {
+            // connection settings are not asked but kept from the previous config
+            let previous = config.ok();
+
+            // stronger key derivation parameters are used for new databases only
+            let kdf_params = match (
+                args.get_one::<u32>("kdf-memory-cost"),
+                args.get_one::<u32>("kdf-iterations"),
+                args.get_one::<u32>("kdf-parallelism"),
+            ) {
+                (None, None, None) => previous.as_ref().and_then(|c| c.kdf_params),
+                (memory_cost, iterations, parallelism) => {
+                    let defaults = KdfParams::default();
+                    let kdf_params = KdfParams {
+                        memory_cost: memory_cost.copied().unwrap_or(defaults.memory_cost),
+                        iterations: iterations.copied().unwrap_or(defaults.iterations),
+                        parallelism: parallelism.copied().unwrap_or(defaults.parallelism),
+                        ..defaults
+                    };
+                    if let Err(err) = kdf_params.validate() {
+                        eprintln!("Key derivation parameters are not valid: {err}");
+                        return 1;
+                    }
+                    Some(kdf_params)
+                }
+            };
+
             // create a new configuration
             let user_name: String = user_prompt("Please enter your user name").unwrap();
             let directory: String = user_prompt("Please enter your database location").unwrap();
 
             let new_config = AppConfig {
+                profile: previous.as_ref().map_or_else(
+                    || {
+                        args.get_one::<String>("profile")
+                            .filter(|name| *name != DEFAULT_PROFILE)
+                            .cloned()
+                    },
+                    |c| c.profile.clone(),
+                ),
                 user_name: user_name.to_owned(),
                 storage: directory.to_owned(),
+                session_url: previous.as_ref().and_then(|c| c.session_url.clone()),
+                accept_invalid_certs: previous.as_ref().is_some_and(|c| c.accept_invalid_certs),
+                ca_certificate: previous.as_ref().and_then(|c| c.ca_certificate.clone()),
+                max_removed_fraction: previous.as_ref().and_then(|c| c.max_removed_fraction),
+                backups: previous.as_ref().and_then(|c| c.backups),
+                kdf_params,
+                secret_storage: args
+                    .get_one::<String>("secret-storage")
+                    .and_then(|s| s.parse().ok())
+                    .or(previous.as_ref().and_then(|c| c.secret_storage)),
+                credential_helper: previous.as_ref().and_then(|c| c.credential_helper.clone()),
             };
 
-            ConfigStorage::update(&new_config).expect("Problem with the config update");
+            config_storage
+                .update(&new_config)
+                .expect("Problem with the config update");
         }
         Some((COMMAND_UPDATE_PASSWORD, _)) => {
             let password: PasswordValue =
                 password_prompt("Please provide your fastmail app specific password").unwrap();
 
             let config: AppConfig = config.expect("Configuration is not created or corrupted");
-            PasswordStorage::update_password(&config.user_name, &password)
-                .expect("Password was not stored");
+            let storage = password_storage.expect("Secret storage is not available");
+            if let Err(err) = storage.update_password(&config.account_name(), &password) {
+                eprintln!("Token was not stored: {err}");
+                return 1;
+            }
 
-            log::info!("Token was stored in keychain");
+            log::info!("Token was stored in the secret storage");
         }
-        Some((COMMAND_REFRESH_DB, _)) => {
+        Some((COMMAND_ROTATE_TOKEN, _)) => {
+            let password: PasswordValue =
+                password_prompt("Please provide your new fastmail app specific password").unwrap();
+
             let config: AppConfig = config.expect("Configuration is not created or corrupted");
-            match refresh_db::<PasswordStorage>(&config) {
-                Ok(()) => (),
+            let storage = password_storage.expect("Secret storage is not available");
+            match rotate_token(storage, &config, password) {
+                Ok(()) => {
+                    println!("Token is stored and the database is encrypted with the new key")
+                }
+                Err(err) => {
+                    eprintln!("Operation failed: {err}");
+                    return 1;
+                }
+            }
+        }
+        Some((COMMAND_KEY_MODE, args)) => {
+            let config: AppConfig = config.expect("Configuration is not created or corrupted");
+            let storage = password_storage.expect("Secret storage is not available");
+            let mode = match args.get_one::<String>("mode").map(String::as_str) {
+                Some("passphrase") => KeyMode::Passphrase(
+                    new_password_prompt("Please choose the database passphrase").unwrap(),
+                ),
+                Some("wrapped-key") => KeyMode::WrappedKey(
+                    new_password_prompt("Please choose the database passphrase").unwrap(),
+                ),
+                _ => KeyMode::Token,
+            };
+            match change_key_mode(storage, &config, mode) {
+                Ok(()) => println!("Database is encrypted with the new key"),
                 Err(err) => eprintln!("Operation failed: {err}"),
             }
         }
+        Some((COMMAND_REFRESH_DB, args)) => {
+            let config: AppConfig = config.expect("Configuration is not created or corrupted");
+            let storage = password_storage.expect("Secret storage is not available");
+            let force = args.get_flag("force");
+            let confirm = |removed: usize, total: usize| {
+                // scripts and cron jobs cannot answer, so the refresh is refused
+                if std::io::stdin().is_terminal() {
+                    confirm_prompt(&format!(
+                        "{removed} of {total} emails disappeared on the server. Store the result?"
+                    ))
+                } else {
+                    Ok(false)
+                }
+            };
+            // cron jobs and CI see that the refresh was refused or failed
+            if let Err(err) = refresh_db(storage, &config, force, confirm) {
+                eprintln!("Operation failed: {err}");
+                return 1;
+            }
+        }
         Some((COMMAND_EXPORT_LUA, args)) => {
             let config: AppConfig = config.expect("Configuration is not created or corrupted");
+            let storage = password_storage.expect("Secret storage is not available");
             let lua_script = args
                 .get_one::<String>("path")
                 .expect("Lua script path is not provided")
                 .to_owned();
             let path = PathBuf::from(lua_script);
-            match export_lua::<PasswordStorage>(&config, &path.as_path()) {
+            let query = args.get_one::<Query>("query");
+            match export_lua(storage, &config, &path.as_path(), query) {
                 Ok(()) => (),
                 Err(err) => eprintln!("Operation failed: {err}"),
             }
         }
-        Some((COMMAND_SHOW_DB, _)) => {
+        Some((COMMAND_SHOW_DB, args)) => {
             let config: AppConfig = config.expect("Configuration is not created or corrupted");
-            match show_emails::<PasswordStorage>(&config) {
+            let storage = password_storage.expect("Secret storage is not available");
+            match show_emails(storage, &config, args.get_one::<Query>("query")) {
                 Ok(()) => (),
                 Err(err) => eprintln!("Operation failed: {err}"),
             }
         }
+        Some((COMMAND_LIST, args)) => {
+            let config: AppConfig = config.expect("Configuration is not created or corrupted");
+            let storage = password_storage.expect("Secret storage is not available");
+            let date = |name: &str| args.get_one::<DateTime<Utc>>(name).copied();
+            let options = ListOptions {
+                filter: EmailFilter {
+                    states: args
+                        .get_many::<String>("state")
+                        .unwrap_or_default()
+                        .map(|s| {
+                            s.parse()
+                                .expect("State is validated by the argument parser")
+                        })
+                        .collect(),
+                    domain: args.get_one::<String>("domain").cloned(),
+                    description: args.get_one::<Regex>("description").cloned(),
+                    created_after: date("created-after"),
+                    created_before: date("created-before"),
+                    last_message_after: date("last-message-after"),
+                    last_message_before: date("last-message-before"),
+                    query: args.get_one::<Query>("query").cloned(),
+                },
+                sort: args.get_one::<String>("sort").map(|s| {
+                    s.parse()
+                        .expect("Sort key is validated by the argument parser")
+                }),
+                reverse: args.get_flag("reverse"),
+                limit: args.get_one::<usize>("limit").copied(),
+                format: args
+                    .get_one::<String>("format")
+                    .expect("Format has a default value")
+                    .parse()
+                    .expect("Format is validated by the argument parser"),
+            };
+            if let Err(err) = list_emails(storage, &config, &options, &mut std::io::stdout().lock())
+            {
+                eprintln!("Operation failed: {err}");
+                return 1;
+            }
+        }
+        Some((COMMAND_GET, args)) => {
+            let config: AppConfig = config.expect("Configuration is not created or corrupted");
+            let storage = password_storage.expect("Secret storage is not available");
+            let target = args
+                .get_one::<String>("target")
+                .expect("Target is not provided");
+            let format = args
+                .get_one::<String>("format")
+                .expect("Format has a default value")
+                .parse()
+                .expect("Format is validated by the argument parser");
+            // exit codes like grep: 1 is not found, 2 is an error
+            match get_emails(
+                storage,
+                &config,
+                target,
+                format,
+                &mut std::io::stdout().lock(),
+            ) {
+                Ok(0) => {
+                    eprintln!("No aliases found for {target}");
+                    return 1;
+                }
+                Ok(_) => (),
+                Err(err) => {
+                    eprintln!("Operation failed: {err}");
+                    return 2;
+                }
+            }
+        }
+        Some((COMMAND_CREATE, args)) => {
+            let config: AppConfig = config.expect("Configuration is not created or corrupted");
+            let storage = password_storage.expect("Secret storage is not available");
+            let state: MaskedEmailState = args
+                .get_one::<String>("state")
+                .expect("State has a default value")
+                .parse()
+                .expect("State is validated by the argument parser");
+            let new_email = NewMaskedEmail {
+                for_domain: args.get_one::<String>("domain").cloned(),
+                description: args.get_one::<String>("description").cloned(),
+                url: args.get_one::<String>("url").cloned(),
+                email_prefix: args.get_one::<String>("prefix").cloned(),
+                state,
+            };
+            match create_email(storage, &config, &new_email) {
+                Ok((created, not_stored)) => {
+                    println!("{}", created.email);
+                    if let Some(err) = not_stored {
+                        eprintln!(
+                            "Warning: {} is created but not stored in the local database, run `refresh-db` to add it: {err}",
+                            created.email
+                        );
+                    }
+                }
+                Err(err) => {
+                    eprintln!("Operation failed: {err}");
+                    return 1;
+                }
+            }
+        }
+        Some((command @ (COMMAND_ENABLE | COMMAND_DISABLE | COMMAND_DELETE), args)) => {
+            let config: AppConfig = config.expect("Configuration is not created or corrupted");
+            let storage = password_storage.expect("Secret storage is not available");
+            let state = match command {
+                COMMAND_ENABLE => MaskedEmailState::Active,
+                COMMAND_DISABLE => MaskedEmailState::Disabled,
+                _ => MaskedEmailState::MarkedForDeletion,
+            };
+            let targets: Vec<String> = match args.get_one::<Query>("query") {
+                Some(query) => {
+                    let matched = match find_emails(storage, &config, Some(query)) {
+                        Ok(matched) => matched,
+                        Err(err) => {
+                            eprintln!("Operation failed: {err}");
+                            return 1;
+                        }
+                    };
+                    if matched.is_empty() {
+                        eprintln!("No aliases match the query");
+                        return 1;
+                    }
+                    if !args.get_flag("yes") {
+                        for email in &matched {
+                            eprintln!("{}: {}", email.email, email.state);
+                        }
+                        // scripts cannot answer, they have to pass --yes
+                        let confirmed = std::io::stdin().is_terminal()
+                            && confirm_prompt(&format!(
+                                "Change {} aliases to {state}?",
+                                matched.len()
+                            ))
+                            .unwrap_or(false);
+                        if !confirmed {
+                            eprintln!("Nothing was changed, use --yes to skip the confirmation");
+                            return 1;
+                        }
+                    }
+                    matched.into_iter().map(|e| e.internal_id).collect()
+                }
+                None => args
+                    .get_many::<String>("emails")
+                    .expect("Emails are not provided")
+                    .cloned()
+                    .collect(),
+            };
+            match update_state(storage, &config, &targets, state) {
+                Ok(result) => {
+                    for id in result.updated {
+                        println!("{id}: {state}");
+                    }
+                    if !result.not_updated.is_empty() {
+                        for (id, reason) in result.not_updated {
+                            eprintln!("{id} was not updated: {reason}");
+                        }
+                        return 1;
+                    }
+                }
+                Err(err) => {
+                    eprintln!("Operation failed: {err}");
+                    return 1;
+                }
+            }
+        }
+        Some((COMMAND_EDIT, args)) => {
+            let config: AppConfig = config.expect("Configuration is not created or corrupted");
+            let storage = password_storage.expect("Secret storage is not available");
+            let target = args
+                .get_one::<String>("email")
+                .expect("Email is not provided");
+            let update = MaskedEmailUpdate {
+                for_domain: args.get_one::<String>("domain").cloned(),
+                description: args.get_one::<String>("description").cloned(),
+                url: args.get_one::<String>("url").cloned(),
+            };
+            let interactive =
+                update.for_domain.is_none() && update.description.is_none() && update.url.is_none();
+
+            let result = edit_email(storage, &config, target, |current| {
+                if interactive {
+                    // show the form pre-filled with the current values
+                    let for_domain = user_prompt_with_initial(
+                        "Domain",
+                        current.web_site.as_deref().unwrap_or_default(),
+                    )?;
+                    let description = user_prompt_with_initial(
+                        "Description",
+                        current.description.as_deref().unwrap_or_default(),
+                    )?;
+                    let url = user_prompt_with_initial(
+                        "Url",
+                        current.integration_url.as_deref().unwrap_or_default(),
+                    )?;
+                    Ok(MaskedEmailUpdate {
+                        for_domain: Some(for_domain),
+                        description: Some(description),
+                        url: Some(url),
+                    })
+                } else {
+                    Ok(update)
+                }
+            });
+            match result {
+                Ok(edited) => println!("{:#?}", edited),
+                Err(err) => {
+                    eprintln!("Operation failed: {err}");
+                    return 1;
+                }
+            }
+        }
+        Some((COMMAND_MIGRATE_DB, _)) => {
+            let config: AppConfig = config.expect("Configuration is not created or corrupted");
+            let storage = password_storage.expect("Secret storage is not available");
+            match migrate_db(storage, &config) {
+                Ok(Some(previous)) => println!(
+                    "Database is migrated from v{previous} to v{}, the previous version is kept as a backup",
+                    db::disk::FORMAT_VERSION
+                ),
+                Ok(None) => println!("Database already has the latest format"),
+                Err(err) => eprintln!("Operation failed: {err}"),
+            }
+        }
+        Some((COMMAND_BACKUP, args)) => {
+            let config: AppConfig = config.expect("Configuration is not created or corrupted");
+            let storage = password_storage.expect("Secret storage is not available");
+            match args.subcommand() {
+                Some((COMMAND_BACKUP_LIST, _)) => match list_backups(&config) {
+                    Ok(backups) if backups.is_empty() => println!("No backups found"),
+                    Ok(backups) => {
+                        for backup in backups {
+                            match backup.snapshot {
+                                Ok(snapshot) => println!(
+                                    "{}: {} emails, updated at {}",
+                                    backup.index, snapshot.records_count, snapshot.last_update
+                                ),
+                                Err(err) => println!("{}: cannot be read ({err})", backup.index),
+                            }
+                        }
+                    }
+                    Err(err) => eprintln!("Operation failed: {err}"),
+                },
+                Some((COMMAND_BACKUP_RESTORE, args)) => {
+                    let index = *args
+                        .get_one::<usize>("index")
+                        .expect("Backup index is not provided");
+                    match restore_backup(storage, &config, index) {
+                        Ok(()) => println!("Backup {index} is restored"),
+                        Err(err) => eprintln!("Operation failed: {err}"),
+                    }
+                }
+                _ => unreachable!("Subcommand is required"),
+            }
+        }
+        Some((COMMAND_PROFILES, args)) => match args.subcommand() {
+            Some((COMMAND_PROFILES_LIST, _)) => match config_storage.list_profiles() {
+                Ok(profiles) if profiles.is_empty() => println!("No profiles found"),
+                Ok(profiles) => {
+                    let selected = config.as_ref().ok().map(AppConfig::profile_name);
+                    for profile in &profiles {
+                        let marker = if selected == Some(profile.profile_name()) {
+                            "*"
+                        } else {
+                            " "
+                        };
+                        println!(
+                            "{marker} {}: {}, {}",
+                            profile.profile_name(),
+                            profile.user_name,
+                            profile.storage
+                        );
+                    }
+                }
+                Err(err) => eprintln!("Operation failed: {err}"),
+            },
+            _ => unreachable!("Subcommand is required"),
+        },
         Some(_) => {
             run_args().render_help();
         }
         None => {}
     }
+    0
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+
+    use crate::{
+        actions::{
+            change_key_mode, export_lua, find_emails, get_emails, list_emails,
+            list_emails::{EmailFilter, ListOptions, OutputFormat},
+            migrate_db, refresh_db, restore_backup, rotate_token, ActionError, KeyMode,
+        },
+        config::{run_args, AppConfig, ConfigReader},
+        db::disk::{DBError, Database, DatabaseContent, KeySource},
+        model::masked_email::MaskedEmailState,
+        run_app,
+        secrets::{
+            encryption::{generate_key, unwrap_key},
+            fastmail::SecureStorage,
+            PasswordValue,
+        },
+        stores_token,
+        testing::{
+            memory::{MemoryConfig, MemoryStorage},
+            mock_jmap::MockJmapServer,
+        },
+    };
+
+    const TOKEN: &str = "mock-token";
+    const USER: &str = "user@example.com";
+
+    /// Application with in-memory storages.
+    struct App {
+        config: MemoryConfig,
+        storage: MemoryStorage,
+    }
+
+    impl App {
+        fn run(&self, args: &[&str]) -> u8 {
+            let args = std::iter::once("masked-email-cli").chain(args.iter().copied());
+            run_app(
+                run_args().get_matches_from(args),
+                &self.config,
+                Ok(&self.storage),
+            )
+        }
+
+        fn load_db(&self, storage: &Path) -> DatabaseContent {
+            let db = Database::init(storage).expect("Database is not created");
+            let key = self
+                .storage
+                .load_key(USER)
+                .unwrap()
+                .expect("Key is not stored");
+            db.load_content(&key).expect("Database cannot be decrypted")
+        }
+    }
+
+    fn setup(server: &MockJmapServer, storage: &Path) -> App {
+        let config = AppConfig {
+            profile: None,
+            user_name: USER.to_owned(),
+            storage: storage.to_str().unwrap().to_owned(),
+            session_url: Some(server.session_url()),
+            accept_invalid_certs: false,
+            ca_certificate: None,
+            max_removed_fraction: None,
+            backups: None,
+            kdf_params: None,
+            secret_storage: None,
+            credential_helper: None,
+        };
+        let app = App {
+            config: MemoryConfig::default(),
+            storage: MemoryStorage::default(),
+        };
+        app.config.update(&config).unwrap();
+        app.storage
+            .update_password(USER, &PasswordValue::from(TOKEN.to_owned()))
+            .unwrap();
+        app
+    }
+
+    #[test]
+    fn refresh_loads_all_emails() {
+        let server = MockJmapServer::start(TOKEN);
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let app = setup(&server, tmp_dir.path());
+
+        server.add_email("first@mock.example", "First");
+        server.add_email("second@mock.example", "Second");
+
+        app.run(&["refresh-db"]);
+
+        let content = app.load_db(tmp_dir.path());
+        assert_eq!(content.emails.len(), 2);
+        assert_eq!(content.jmap_state.as_deref(), Some("2"));
+    }
+
+    #[test]
+    fn newer_database_is_not_replaced() {
+        let server = MockJmapServer::start(TOKEN);
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let app = setup(&server, tmp_dir.path());
+
+        server.add_email("first@mock.example", "First");
+        app.run(&["refresh-db"]);
+        let key = app
+            .storage
+            .load_key(USER)
+            .unwrap()
+            .expect("Key is not stored");
+
+        // the file of a future application version
+        let path = tmp_dir.path().join("masked_mails.db.enc");
+        let mut content = std::fs::read(&path).unwrap();
+        content[3] = 9;
+        std::fs::write(&path, &content).unwrap();
+
+        server.add_email("second@mock.example", "Second");
+        app.run(&["refresh-db"]);
+        app.run(&["create", "--prefix", "shop"]);
+
+        assert_eq!(std::fs::read(&path).unwrap(), content);
+        assert_eq!(
+            app.storage.load_key(USER).unwrap().unwrap().value,
+            key.value
+        );
+        let config = app.config.try_load().unwrap();
+        assert!(matches!(
+            migrate_db(&app.storage, &config),
+            Err(ActionError::Database(DBError::UnsupportedFormatVersion(9)))
+        ));
+    }
+
+    #[test]
+    fn refresh_applies_changes() {
+        let server = MockJmapServer::start(TOKEN);
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let app = setup(&server, tmp_dir.path());
+
+        let first = server.add_email("first@mock.example", "First");
+        server.add_email("second@mock.example", "Second");
+        server.add_email("third@mock.example", "Third");
+        server.add_email("fourth@mock.example", "Fourth");
+        app.run(&["refresh-db"]);
+
+        server.remove_email(&first);
+        let fifth = server.add_email("fifth@mock.example", "Fifth");
+        app.run(&["refresh-db"]);
+
+        let content = app.load_db(tmp_dir.path());
+        let mut emails: Vec<&str> = content.emails.iter().map(|e| e.email.as_str()).collect();
+        emails.sort();
+        assert_eq!(
+            emails,
+            vec![
+                "fifth@mock.example",
+                "fourth@mock.example",
+                "second@mock.example",
+                "third@mock.example"
+            ]
+        );
+        assert!(content.emails.iter().any(|e| e.internal_id == fifth));
+        assert_eq!(content.jmap_state.as_deref(), Some("6"));
+    }
+
+    #[test]
+    fn backup_restore_returns_previous_version() {
+        let server = MockJmapServer::start(TOKEN);
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let app = setup(&server, tmp_dir.path());
+
+        server.add_email("first@mock.example", "First");
+        app.run(&["refresh-db"]);
+        server.add_email("second@mock.example", "Second");
+        app.run(&["refresh-db"]);
+        assert_eq!(app.load_db(tmp_dir.path()).emails.len(), 2);
+
+        app.run(&["backup", "restore", "1"]);
+
+        let content = app.load_db(tmp_dir.path());
+        assert_eq!(content.emails.len(), 1);
+        assert_eq!(content.jmap_state.as_deref(), Some("1"));
+    }
+
+    #[test]
+    fn refresh_falls_back_to_full_load() {
+        let server = MockJmapServer::start(TOKEN);
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let app = setup(&server, tmp_dir.path());
+
+        server.add_email("first@mock.example", "First");
+        app.run(&["refresh-db"]);
+
+        server.add_email("second@mock.example", "Second");
+        server.forget_history();
+        app.run(&["refresh-db"]);
+
+        let content = app.load_db(tmp_dir.path());
+        assert_eq!(content.emails.len(), 2);
+        assert_eq!(content.jmap_state.as_deref(), Some("2"));
+    }
+
+    #[test]
+    fn create_adds_email_to_database() {
+        let server = MockJmapServer::start(TOKEN);
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let app = setup(&server, tmp_dir.path());
+
+        server.add_email("first@mock.example", "First");
+        app.run(&["refresh-db"]);
+
+        app.run(&[
+            "create",
+            "--domain",
+            "https://shop.example",
+            "--description",
+            "Shop",
+            "--prefix",
+            "shop",
+        ]);
+
+        let content = app.load_db(tmp_dir.path());
+        let created = content
+            .emails
+            .iter()
+            .find(|e| e.email.starts_with("shop."))
+            .expect("Email is not stored");
+        assert_eq!(created.description.as_deref(), Some("Shop"));
+        assert_eq!(created.web_site.as_deref(), Some("https://shop.example"));
+        assert_eq!(created.state, MaskedEmailState::Active);
+
+        let remote = server
+            .email(&created.internal_id)
+            .expect("Email is not created");
+        assert_eq!(remote["forDomain"], "https://shop.example");
+    }
+
+    #[test]
+    fn disable_updates_state() {
+        let server = MockJmapServer::start(TOKEN);
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let app = setup(&server, tmp_dir.path());
+
+        let first = server.add_email("first@mock.example", "First");
+        server.add_email("second@mock.example", "Second");
+        app.run(&["refresh-db"]);
+
+        assert_eq!(app.run(&["disable", "first@mock.example"]), 0);
+
+        assert_eq!(server.email(&first).unwrap()["state"], "disabled");
+        let content = app.load_db(tmp_dir.path());
+        for email in content.emails {
+            if email.internal_id == first {
+                assert_eq!(email.state, MaskedEmailState::Disabled);
+            } else {
+                assert_eq!(email.state, MaskedEmailState::Active);
+            }
+        }
+    }
+
+    #[test]
+    fn failed_commands_exit_with_error() {
+        let server = MockJmapServer::start(TOKEN);
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let app = setup(&server, tmp_dir.path());
+
+        // the database is not created yet
+        assert_eq!(app.run(&["list"]), 1);
+        assert_eq!(app.run(&["disable", "first@mock.example"]), 1);
+        assert_eq!(app.run(&["edit", "first@mock.example", "--url", ""]), 1);
+
+        let first = server.add_email("first@mock.example", "First");
+        app.run(&["refresh-db"]);
+        assert_eq!(app.run(&["list"]), 0);
+
+        // the server doesn't know the email anymore
+        server.remove_email(&first);
+        assert_eq!(app.run(&["disable", "first@mock.example"]), 1);
+
+        server.fail_method("MaskedEmail/set", "forbidden");
+        assert_eq!(app.run(&["create", "--prefix", "shop"]), 1);
+    }
+
+    #[test]
+    fn disable_by_query_updates_matching_emails() {
+        let server = MockJmapServer::start(TOKEN);
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let app = setup(&server, tmp_dir.path());
+
+        let first = server.add_email("first@mock.example", "Shop");
+        let second = server.add_email("second@mock.example", "News");
+        app.run(&["refresh-db"]);
+
+        app.run(&["disable", "--query", "description:shop", "--yes"]);
+
+        assert_eq!(server.email(&first).unwrap()["state"], "disabled");
+        assert_eq!(server.email(&second).unwrap()["state"], "enabled");
+    }
+
+    #[test]
+    fn edit_updates_description() {
+        let server = MockJmapServer::start(TOKEN);
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let app = setup(&server, tmp_dir.path());
+
+        let first = server.add_email("first@mock.example", "First");
+        app.run(&["refresh-db"]);
+
+        app.run(&["edit", &first, "--description", "Renamed", "--url", ""]);
+
+        assert_eq!(server.email(&first).unwrap()["description"], "Renamed");
+        let content = app.load_db(tmp_dir.path());
+        assert_eq!(content.emails[0].description.as_deref(), Some("Renamed"));
+        assert_eq!(content.emails[0].integration_url, None);
+    }
+
+    #[test]
+    fn export_runs_lua_script() {
+        let server = MockJmapServer::start(TOKEN);
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let app = setup(&server, tmp_dir.path());
+
+        server.add_email("first@mock.example", "First");
+        app.run(&["refresh-db"]);
+
+        let mut script = PathBuf::from(env!("CARGO_MANIFEST_DIR"));
+        script.push("lua/tsv.lua");
+
+        let config = app.config.try_load().unwrap();
+        export_lua(&app.storage, &config, &script, None).expect("Export failed");
+    }
+
+    #[test]
+    fn list_prints_filtered_emails() {
+        let server = MockJmapServer::start(TOKEN);
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let app = setup(&server, tmp_dir.path());
+
+        server.add_email("first@mock.example", "First");
+        server.add_email("second@mock.example", "Second");
+        app.run(&["refresh-db"]);
+        app.run(&["disable", "second@mock.example"]);
+
+        let config = app.config.try_load().unwrap();
+        let options = ListOptions {
+            filter: EmailFilter {
+                states: vec![MaskedEmailState::Disabled],
+                ..Default::default()
+            },
+            sort: None,
+            reverse: false,
+            limit: None,
+            format: OutputFormat::Ndjson,
+        };
+        let mut out = Vec::new();
+        list_emails(&app.storage, &config, &options, &mut out).expect("List failed");
+
+        let lines: Vec<serde_json::Value> = String::from_utf8(out)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(lines.len(), 1);
+        assert_eq!(lines[0]["email"], "second@mock.example");
+        assert_eq!(lines[0]["state"], "disabled");
+    }
+
+    #[test]
+    fn get_finds_email_by_address_or_website() {
+        let server = MockJmapServer::start(TOKEN);
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let app = setup(&server, tmp_dir.path());
+
+        server.add_email("first@mock.example", "First");
+        app.run(&["refresh-db"]);
+        app.run(&[
+            "edit",
+            "first@mock.example",
+            "--domain",
+            "https://www.shop.example/login",
+        ]);
+
+        assert_eq!(app.run(&["get", "FIRST@mock.example"]), 0);
+        assert_eq!(app.run(&["get", "unknown@mock.example"]), 1);
+
+        let config = app.config.try_load().unwrap();
+        let mut out = Vec::new();
+        let found = get_emails(
+            &app.storage,
+            &config,
+            "shop.example",
+            OutputFormat::Ndjson,
+            &mut out,
+        )
+        .expect("Get failed");
+        assert_eq!(found, 1);
+        let record: serde_json::Value = serde_json::from_slice(&out).unwrap();
+        assert_eq!(record["email"], "first@mock.example");
+        assert_eq!(record["domain"], "https://www.shop.example/login");
+    }
+
+    #[test]
+    fn key_is_derived_on_new_machine() {
+        let server = MockJmapServer::start(TOKEN);
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let app = setup(&server, tmp_dir.path());
+
+        server.add_email("first@mock.example", "First");
+        app.run(&["refresh-db"]);
+
+        // only the token is stored on the new machine
+        app.storage.forget_key(USER);
+        app.run(&["disable", "first@mock.example"]);
+
+        assert!(app.storage.load_key(USER).unwrap().is_some());
+        let content = app.load_db(tmp_dir.path());
+        assert_eq!(content.emails[0].state, MaskedEmailState::Disabled);
+    }
+
+    #[test]
+    fn key_from_another_token_is_not_stored() {
+        let server = MockJmapServer::start(TOKEN);
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let app = setup(&server, tmp_dir.path());
+
+        server.add_email("first@mock.example", "First");
+        app.run(&["refresh-db"]);
+        app.run(&["refresh-db"]);
+
+        app.storage.forget_key(USER);
+        app.storage
+            .update_password(USER, &PasswordValue::from("another-token".to_owned()))
+            .unwrap();
+
+        let config = app.config.try_load().unwrap();
+        let result = restore_backup(&app.storage, &config, 1);
+        assert!(matches!(result, Err(ActionError::KeyMismatch)));
+        assert!(app.storage.load_key(USER).unwrap().is_none());
+    }
+
+    #[test]
+    fn token_is_stored_only_by_its_commands() {
+        let parse = |args: &[&str]| {
+            run_args()
+                .get_matches_from(std::iter::once("masked-email-cli").chain(args.iter().copied()))
+        };
+        assert_eq!(
+            stores_token(&parse(&["update-password"])),
+            Some("update-password")
+        );
+        assert_eq!(
+            stores_token(&parse(&["--token-file", "-", "rotate-token"])),
+            Some("rotate-token")
+        );
+        assert_eq!(stores_token(&parse(&["refresh-db"])), None);
+        assert_eq!(stores_token(&parse(&["list"])), None);
+    }
+
+    #[test]
+    fn key_is_checked_for_empty_database() {
+        let server = MockJmapServer::start(TOKEN);
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let app = setup(&server, tmp_dir.path());
+
+        app.run(&["refresh-db"]);
+        assert_eq!(Database::init(tmp_dir.path()).unwrap().records_count, 0);
+
+        app.storage.forget_key(USER);
+        app.storage
+            .update_password(USER, &PasswordValue::from("another-token".to_owned()))
+            .unwrap();
+
+        let config = app.config.try_load().unwrap();
+        let result = find_emails(&app.storage, &config, None);
+        assert!(matches!(result, Err(ActionError::KeyMismatch)));
+        assert!(app.storage.load_key(USER).unwrap().is_none());
+    }
+
+    #[test]
+    fn rotate_token_reencrypts_database() {
+        let server = MockJmapServer::start(TOKEN);
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let app = setup(&server, tmp_dir.path());
+
+        server.add_email("first@mock.example", "First");
+        app.run(&["refresh-db"]);
+        let old_salt = Database::init(tmp_dir.path()).unwrap().key_derivation_salt;
+
+        // the token is rejected until the server knows it
+        let config = app.config.try_load().unwrap();
+        let new_token = || PasswordValue::from("new-token".to_owned());
+        assert!(rotate_token(&app.storage, &config, new_token()).is_err());
+
+        server.set_token("new-token");
+        rotate_token(&app.storage, &config, new_token()).expect("Token is not rotated");
+
+        let account = app.storage.load_password(USER).unwrap().unwrap();
+        assert_eq!(account.bearer.value, "new-token");
+        assert_eq!(app.load_db(tmp_dir.path()).emails.len(), 1);
+
+        // the key is derived from the new token and the new salt on another machine
+        let db = Database::init(tmp_dir.path()).unwrap();
+        assert_ne!(db.key_derivation_salt, old_salt);
+        let key = generate_key(&new_token(), &db.key_derivation_salt, &db.kdf_params).unwrap();
+        assert_eq!(db.load_content(&key).unwrap().emails.len(), 1);
+    }
+
+    #[test]
+    fn failed_rotation_keeps_the_old_secrets() {
+        let server = MockJmapServer::start(TOKEN);
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let app = setup(&server, tmp_dir.path());
+
+        server.add_email("first@mock.example", "First");
+        app.run(&["refresh-db"]);
+        let old_salt = Database::init(tmp_dir.path()).unwrap().key_derivation_salt;
+
+        server.set_token("new-token");
+        app.storage.lock_keys(true);
+        let config = app.config.try_load().unwrap();
+        let result = rotate_token(
+            &app.storage,
+            &config,
+            PasswordValue::from("new-token".to_owned()),
+        );
+        assert!(matches!(result, Err(ActionError::PasswordStorage(_))));
+
+        // the database and both secrets are not changed
+        app.storage.lock_keys(false);
+        let db = Database::init(tmp_dir.path()).unwrap();
+        assert_eq!(db.key_derivation_salt, old_salt);
+        let account = app.storage.load_password(USER).unwrap().unwrap();
+        assert_eq!(account.bearer.value, TOKEN);
+        assert_eq!(app.load_db(tmp_dir.path()).emails.len(), 1);
+    }
+
+    #[test]
+    fn changed_token_replaces_database_only_with_force() {
+        let server = MockJmapServer::start(TOKEN);
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let app = setup(&server, tmp_dir.path());
+
+        server.add_email("first@mock.example", "First");
+        app.run(&["refresh-db"]);
+
+        // the token is replaced without `rotate-token` on a new machine
+        server.set_token("new-token");
+        server.add_email("second@mock.example", "Second");
+        app.storage.forget_key(USER);
+        let new_token = || PasswordValue::from("new-token".to_owned());
+        app.storage.update_password(USER, &new_token()).unwrap();
+        let old_key = || {
+            let db = Database::init(tmp_dir.path()).unwrap();
+            generate_key(
+                &PasswordValue::from(TOKEN.to_owned()),
+                &db.key_derivation_salt,
+                &db.kdf_params,
+            )
+            .unwrap()
+        };
+
+        let config = app.config.try_load().unwrap();
+        let result = refresh_db(&app.storage, &config, false, |_, _| Ok(true));
+        assert!(matches!(result, Err(ActionError::KeyMismatch)));
+        // nothing is created on the server when the database cannot be updated
+        app.run(&["create", "--prefix", "shop"]);
+        assert!(app.storage.load_key(USER).unwrap().is_none());
+        let db = Database::init(tmp_dir.path()).unwrap();
+        assert_eq!(db.load_content(&old_key()).unwrap().emails.len(), 1);
+
+        app.run(&["refresh-db", "--force"]);
+
+        let db = Database::init(tmp_dir.path()).unwrap();
+        let key = generate_key(&new_token(), &db.key_derivation_salt, &db.kdf_params).unwrap();
+        let content = db.load_content(&key).unwrap();
+        assert_eq!(content.emails.len(), 2);
+        assert!(content.emails.iter().all(|e| !e.email.starts_with("shop.")));
+        // the previous version can be decrypted with the old token
+        let backup = &db.backups().unwrap()[0];
+        let snapshot = backup.snapshot.as_ref().unwrap();
+        assert_eq!(snapshot.load_content(&old_key()).unwrap().emails.len(), 1);
+    }
+
+    #[test]
+    fn wrapped_key_survives_token_rotation() {
+        let server = MockJmapServer::start(TOKEN);
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let app = setup(&server, tmp_dir.path());
+
+        server.add_email("first@mock.example", "First");
+        app.run(&["refresh-db"]);
+
+        let config = app.config.try_load().unwrap();
+        let passphrase = || PasswordValue::from("my passphrase".to_owned());
+        change_key_mode(&app.storage, &config, KeyMode::WrappedKey(passphrase()))
+            .expect("Key mode is not changed");
+
+        // the token is replaced but the database keeps its key
+        let db = Database::init(tmp_dir.path()).unwrap();
+        server.set_token("new-token");
+        rotate_token(
+            &app.storage,
+            &config,
+            PasswordValue::from("new-token".to_owned()),
+        )
+        .expect("Token is not rotated");
+        let rotated = Database::init(tmp_dir.path()).unwrap();
+        assert_eq!(rotated.key_derivation_salt, db.key_derivation_salt);
+
+        // the passphrase unwraps the key on another machine
+        let KeySource::WrappedKey(wrapped) = &rotated.key_source else {
+            panic!("Key is not wrapped");
+        };
+        let wrapping_key = generate_key(
+            &passphrase(),
+            &rotated.key_derivation_salt,
+            &rotated.kdf_params,
+        )
+        .unwrap();
+        let key = unwrap_key(&wrapping_key, wrapped).unwrap();
+        assert_eq!(rotated.load_content(&key).unwrap().emails.len(), 1);
+        assert_eq!(app.load_db(tmp_dir.path()).emails.len(), 1);
+    }
+
+    #[test]
+    fn passphrase_key_does_not_depend_on_token() {
+        let server = MockJmapServer::start(TOKEN);
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let app = setup(&server, tmp_dir.path());
+
+        server.add_email("first@mock.example", "First");
+        app.run(&["refresh-db"]);
+
+        let config = app.config.try_load().unwrap();
+        let passphrase = || PasswordValue::from("my passphrase".to_owned());
+        change_key_mode(&app.storage, &config, KeyMode::Passphrase(passphrase()))
+            .expect("Key mode is not changed");
+
+        let db = Database::init(tmp_dir.path()).unwrap();
+        assert_eq!(db.key_source, KeySource::Passphrase);
+        let key = generate_key(&passphrase(), &db.key_derivation_salt, &db.kdf_params).unwrap();
+        assert_eq!(db.load_content(&key).unwrap().emails.len(), 1);
+
+        // refresh keeps using the passphrase key
+        server.add_email("second@mock.example", "Second");
+        app.run(&["refresh-db"]);
+        let db = Database::init(tmp_dir.path()).unwrap();
+        assert_eq!(db.load_content(&key).unwrap().emails.len(), 2);
+    }
+
+    #[test]
+    fn failed_method_keeps_database() {
+        let server = MockJmapServer::start(TOKEN);
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let app = setup(&server, tmp_dir.path());
+
+        server.add_email("first@mock.example", "First");
+        app.run(&["refresh-db"]);
+
+        // the full reload fails on the server side
+        server.add_email("second@mock.example", "Second");
+        server.forget_history();
+        server.fail_method("MaskedEmail/get", "forbidden");
+        app.run(&["refresh-db"]);
+
+        let content = app.load_db(tmp_dir.path());
+        assert_eq!(content.emails.len(), 1);
+        assert_eq!(content.jmap_state.as_deref(), Some("1"));
+    }
+
+    #[test]
+    fn refresh_refuses_mass_removal() {
+        let server = MockJmapServer::start(TOKEN);
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let app = setup(&server, tmp_dir.path());
+
+        let ids: Vec<String> = (0..4)
+            .map(|i| server.add_email(&format!("email{i}@mock.example"), "Email"))
+            .collect();
+        app.run(&["refresh-db"]);
+
+        ids.iter().skip(1).for_each(|id| server.remove_email(id));
+
+        let config = app.config.try_load().unwrap();
+        let res = refresh_db(&app.storage, &config, false, |removed, total| {
+            assert_eq!((removed, total), (3, 4));
+            Ok(false)
+        });
+        assert!(matches!(
+            res,
+            Err(ActionError::DestructiveRefresh {
+                removed: 3,
+                total: 4
+            })
+        ));
+        assert_eq!(app.load_db(tmp_dir.path()).emails.len(), 4);
+
+        // confirmed by the user
+        refresh_db(&app.storage, &config, false, |_, _| Ok(true)).unwrap();
+        assert_eq!(app.load_db(tmp_dir.path()).emails.len(), 1);
+    }
+
+    #[test]
+    fn forced_refresh_stores_empty_list() {
+        let server = MockJmapServer::start(TOKEN);
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let app = setup(&server, tmp_dir.path());
+
+        let first = server.add_email("first@mock.example", "First");
+        app.run(&["refresh-db"]);
+
+        server.remove_email(&first);
+        app.run(&["refresh-db", "--force"]);
+
+        let content = app.load_db(tmp_dir.path());
+        assert!(content.emails.is_empty());
+        // the empty database remembers the state for the next refresh
+        assert!(content.jmap_state.is_some());
+    }
+
+    #[test]
+    fn refresh_fails_with_wrong_token() {
+        let server = MockJmapServer::start("another-token");
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let app = setup(&server, tmp_dir.path());
+
+        server.add_email("first@mock.example", "First");
+        assert_eq!(app.run(&["refresh-db"]), 1);
+
+        assert!(Database::init(tmp_dir.path()).is_err());
+    }
 }
@@ -1,9 +1,9 @@
-use std::fmt::Display;
+use std::{fmt::Display, str::FromStr};
 
 use chrono::{DateTime, Utc};
 use serde::{Deserialize, Serialize};
 
-#[derive(Serialize, Deserialize, PartialEq, Debug)]
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone, Copy)]
 pub enum MaskedEmailState {
     TemporalPending,
     Active,
@@ -22,7 +22,21 @@ impl Display for MaskedEmailState {
     }
 }
 
-#[derive(Serialize, Deserialize, PartialEq, Debug)]
+impl FromStr for MaskedEmailState {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "pending" => Ok(MaskedEmailState::TemporalPending),
+            "enabled" => Ok(MaskedEmailState::Active),
+            "disabled" => Ok(MaskedEmailState::Disabled),
+            "deleted" => Ok(MaskedEmailState::MarkedForDeletion),
+            other => Err(format!("unknown masked email state: {other}")),
+        }
+    }
+}
+
+#[derive(Serialize, Deserialize, PartialEq, Debug, Clone)]
 pub struct MaskedEmail {
     pub internal_id: String,
     pub email: String,
@@ -2,9 +2,14 @@ use std::ops::{Deref, DerefMut};
 
 use zeroize::{Zeroize, ZeroizeOnDrop};
 
+pub mod credential_helper;
+pub mod encrypted_file;
 pub mod encryption;
 pub mod fastmail;
+#[cfg(target_os = "macos")]
 pub mod keychain;
+pub mod registry;
+pub mod token_source;
 
 const KEY_SIZE_BYTES: usize = 32;
 pub type AESKey = [u8; KEY_SIZE_BYTES];
@@ -0,0 +1,271 @@
+use std::{
+    io::Write,
+    process::{Command, Stdio},
+};
+
+use base64::{engine::general_purpose, Engine};
+
+use super::{
+    fastmail::{FastMailAccount, PasswordStorageError, Result, SecureStorage},
+    AESKey, AesKeyValue, PasswordValue,
+};
+
+const FASTMAIL_SERVICE_NAME: &str = "fast-mail-cli";
+const AES_SERVICE_NAME: &str = "fast-mail-cli-aes";
+
+/// External program that keeps secrets, for instance a wrapper around `pass`, `gopass`, 1Password CLI
+/// or Vault agent. It is called git-credential style: `<command> get|store` with `key=value` lines
+/// (`service`, `username` and `password` for `store`) and an empty line on stdin. `get` prints
+/// `password=<secret>` or nothing if the secret is not found, `store` replaces the previous value.
+/// Every call runs the command once.
+pub struct CredentialHelper {
+    command: String,
+}
+
+impl CredentialHelper {
+    /// Make the helper storage.
+    ///
+    /// # Arguments
+    ///
+    /// * `command` - shell command, the action is added as the last argument
+    pub fn new(command: &str) -> Self {
+        CredentialHelper {
+            command: command.to_owned(),
+        }
+    }
+
+    /// Run the helper action.
+    ///
+    /// # Arguments
+    ///
+    /// * `action` - get or store
+    /// * `attributes` - key=value pairs that are sent to stdin
+    ///
+    /// # Returns
+    ///
+    /// helper output
+    fn run(&self, action: &str, attributes: &[(&str, &str)]) -> Result<String> {
+        let mut command = self.make_command(action);
+        let mut child = command
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::inherit())
+            .spawn()
+            .map_err(|e| PasswordStorageError(format!("credential helper cannot be run: {e}")))?;
+
+        {
+            let mut stdin = child.stdin.take().expect("Stdin is piped");
+            let mut input = String::new();
+            for (key, value) in attributes {
+                input.push_str(&format!("{key}={value}\n"));
+            }
+            input.push('\n');
+            stdin
+                .write_all(input.as_bytes())
+                .map_err(|e| PasswordStorageError(e.to_string()))?;
+        }
+
+        let output = child
+            .wait_with_output()
+            .map_err(|e| PasswordStorageError(e.to_string()))?;
+        if output.status.success() {
+            String::from_utf8(output.stdout).map_err(|_| {
+                PasswordStorageError("credential helper output is not UTF-8".to_owned())
+            })
+        } else {
+            Err(PasswordStorageError(format!(
+                "credential helper `{action}` failed: {}",
+                output.status
+            )))
+        }
+    }
+
+    /// Make the command the same way git does: the helper is run by the shell.
+    #[cfg(unix)]
+    fn make_command(&self, action: &str) -> Command {
+        let mut command = Command::new("sh");
+        command
+            .arg("-c")
+            .arg(format!("{} \"$@\"", self.command))
+            .arg(&self.command)
+            .arg(action);
+        command
+    }
+
+    #[cfg(not(unix))]
+    fn make_command(&self, action: &str) -> Command {
+        let mut parts = self.command.split_whitespace();
+        let mut command = Command::new(parts.next().unwrap_or_default());
+        command.args(parts).arg(action);
+        command
+    }
+
+    fn get(&self, service: &str, username: &str) -> Result<Option<PasswordValue>> {
+        let output = self.run("get", &[("service", service), ("username", username)])?;
+        let password = output
+            .lines()
+            .find_map(|line| line.strip_prefix("password="))
+            .map(|value| PasswordValue::from(value.to_owned()));
+        if password.is_none() {
+            log::info!("Password was not found: [{service}] / [{username}]");
+        }
+        Ok(password)
+    }
+
+    fn store(&self, service: &str, username: &str, secret: &PasswordValue) -> Result<()> {
+        // `store` replaces the old value, it is not erased first to keep it if the store fails
+        self.run(
+            "store",
+            &[
+                ("service", service),
+                ("username", username),
+                ("password", &secret.value),
+            ],
+        )?;
+        log::info!("New password was stored by the credential helper: [{service}] / [{username}]");
+        Ok(())
+    }
+}
+
+impl SecureStorage for CredentialHelper {
+    fn update_password(&self, username: &str, bearer: &PasswordValue) -> Result<()> {
+        self.store(FASTMAIL_SERVICE_NAME, username, bearer)
+    }
+
+    fn load_password(&self, username: &str) -> Result<Option<FastMailAccount>> {
+        self.get(FASTMAIL_SERVICE_NAME, username)
+            .map(|maybe_pass| maybe_pass.map(|p| FastMailAccount { bearer: p }))
+    }
+
+    fn load_key(&self, username: &str) -> Result<Option<AesKeyValue>> {
+        let encoded = self.get(AES_SERVICE_NAME, username)?;
+        Ok(encoded.and_then(|base64| {
+            match general_purpose::STANDARD
+                .decode(&base64.value)
+                .ok()
+                .and_then(|vec| AESKey::try_from(vec).ok())
+            {
+                Some(aes) => Some(AesKeyValue { value: aes }),
+                None => {
+                    log::warn!("[{AES_SERVICE_NAME}] / [{username}] is not a base64 encoded key");
+                    None
+                }
+            }
+        }))
+    }
+
+    fn update_key(&self, username: &str, key: &AesKeyValue) -> Result<()> {
+        let encoded = PasswordValue::from(general_purpose::STANDARD.encode(key.value));
+        self.store(AES_SERVICE_NAME, username, &encoded)
+    }
+}
+
+#[cfg(all(test, unix))]
+mod tests {
+    use std::{fs, path::Path};
+
+    use crate::secrets::PasswordValue;
+
+    use super::CredentialHelper;
+
+    /// Helper that keeps every secret in a separate file of the directory.
+    fn write_helper(dir: &Path) -> String {
+        let script = dir.join("helper.sh");
+        fs::write(
+            &script,
+            format!(
+                r#"while IFS='=' read -r key value; do
+  case "$key" in
+    service) service=$value ;;
+    username) username=$value ;;
+    password) password=$value ;;
+    "") break ;;
+  esac
+done
+file="{}/$service-$username"
+case "$1" in
+  get) if [ -f "$file" ]; then printf 'password=%s\n' "$(cat "$file")"; fi ;;
+  store) printf '%s' "$password" > "$file" ;;
+esac
+"#,
+                dir.display()
+            ),
+        )
+        .unwrap();
+        format!("sh {}", script.display())
+    }
+
+    #[test]
+    fn store_and_get_secrets() {
+        // make new tmp directory
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let command = write_helper(tmp_dir.path());
+        let helper = CredentialHelper::new(&command);
+
+        assert!(helper
+            .get("fast-mail-cli", "user@example.com")
+            .unwrap()
+            .is_none());
+
+        helper
+            .store(
+                "fast-mail-cli",
+                "user@example.com",
+                &PasswordValue::from("token".to_owned()),
+            )
+            .expect("Secret is not stored");
+        helper
+            .store(
+                "fast-mail-cli",
+                "user@example.com",
+                &PasswordValue::from("new-token".to_owned()),
+            )
+            .expect("Secret is not replaced");
+
+        let loaded = helper
+            .get("fast-mail-cli", "user@example.com")
+            .unwrap()
+            .expect("Secret is not found");
+        assert_eq!(loaded.value, "new-token");
+    }
+
+    #[test]
+    fn failed_store_keeps_old_secret() {
+        // make new tmp directory
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let command = write_helper(tmp_dir.path());
+        let helper = CredentialHelper::new(&command);
+
+        helper
+            .store(
+                "fast-mail-cli",
+                "user@example.com",
+                &PasswordValue::from("token".to_owned()),
+            )
+            .expect("Secret is not stored");
+
+        // get works but store fails
+        let failing = CredentialHelper::new(&format!(
+            "f() {{ if [ \"$1\" = store ]; then exit 1; fi; {command} \"$1\"; }}; f"
+        ));
+        assert!(failing
+            .store(
+                "fast-mail-cli",
+                "user@example.com",
+                &PasswordValue::from("new-token".to_owned()),
+            )
+            .is_err());
+
+        let loaded = helper
+            .get("fast-mail-cli", "user@example.com")
+            .unwrap()
+            .expect("Secret is lost");
+        assert_eq!(loaded.value, "token");
+    }
+
+    #[test]
+    fn failed_helper_is_reported() {
+        let helper = CredentialHelper::new("false");
+        assert!(helper.get("fast-mail-cli", "user@example.com").is_err());
+    }
+}
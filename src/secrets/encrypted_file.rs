@@ -0,0 +1,354 @@
+use std::{
+    collections::HashMap,
+    fs, io,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use serde::{Deserialize, Serialize};
+use zeroize::Zeroize;
+
+use crate::cli::{new_password_prompt, password_prompt};
+
+use super::{
+    encryption::{
+        decrypt_in_place, encrypt_in_place, generate_key, generate_new_salt, KdfParams,
+        KeyDerivationSalt, NONCE_SIZE_BYTES,
+    },
+    fastmail::{FastMailAccount, PasswordStorageError, Result, SecureStorage},
+    AESKey, AesKeyValue, PasswordValue,
+};
+
+const SECRETS_FILE_NAME: &str = "maskedemail-cli.secrets";
+const TEMP_FILE_EXTENSION: &str = "tmp";
+const FILE_SIGNATURE: [u8; 4] = [b'M', b'E', b'S', 1u8];
+
+/// Tokens and AES keys of all users, the whole structure is encrypted in the file.
+#[derive(Default, Deserialize, Serialize)]
+struct Secrets {
+    passwords: HashMap<String, String>,
+    keys: HashMap<String, AESKey>,
+}
+
+impl Drop for Secrets {
+    fn drop(&mut self) {
+        self.passwords.values_mut().for_each(Zeroize::zeroize);
+        self.keys.values_mut().for_each(Zeroize::zeroize);
+    }
+}
+
+/// File format, everything except the secrets is not encrypted.
+#[derive(Deserialize, Serialize)]
+struct SecretsFile {
+    file_signature: [u8; 4],
+    salt: KeyDerivationSalt,
+    kdf_params: KdfParams,
+    nonce: [u8; NONCE_SIZE_BYTES],
+    tag: [u8; 16],
+    content: Vec<u8>,
+}
+
+/// Opened secrets file with the key that decrypts it.
+struct SecretsStore {
+    path: PathBuf,
+    salt: KeyDerivationSalt,
+    kdf_params: KdfParams,
+    /// empty until the new file is saved
+    key: Option<AesKeyValue>,
+    secrets: Secrets,
+}
+
+impl SecretsStore {
+    /// Open and decrypt the file. Empty secrets are returned without the passphrase if the file doesn't
+    /// exist, the passphrase of the new file is asked when it is saved.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - secrets file
+    /// * `passphrase` - passphrase of the existing file
+    fn open<F>(path: &Path, passphrase: F) -> Result<Self>
+    where
+        F: FnOnce() -> io::Result<PasswordValue>,
+    {
+        if !path.exists() {
+            return Ok(SecretsStore {
+                path: path.to_owned(),
+                salt: generate_new_salt(),
+                kdf_params: KdfParams::default(),
+                key: None,
+                secrets: Secrets::default(),
+            });
+        }
+
+        let file = fs::read(path)?;
+        let mut file: SecretsFile = bincode::deserialize(&file)
+            .ok()
+            .filter(|f: &SecretsFile| f.file_signature == FILE_SIGNATURE)
+            .ok_or_else(|| {
+                PasswordStorageError(format!("{} has an incorrect format", path.display()))
+            })?;
+
+        let key = generate_key(&passphrase()?, &file.salt, &file.kdf_params)?;
+        decrypt_in_place(
+            &key,
+            &file.nonce.into(),
+            &file.salt,
+            &mut file.content,
+            &file.tag.into(),
+        )
+        .map_err(|_| PasswordStorageError("passphrase is not correct".to_owned()))?;
+
+        let secrets = bincode::deserialize(&file.content)
+            .map_err(|_| PasswordStorageError(format!("{} is corrupted", path.display())))?;
+        file.content.zeroize();
+
+        Ok(SecretsStore {
+            path: path.to_owned(),
+            salt: file.salt,
+            kdf_params: file.kdf_params,
+            key: Some(key),
+            secrets,
+        })
+    }
+
+    /// Encrypt the secrets and atomically replace the file. Only the owner can read the file.
+    ///
+    /// # Arguments
+    ///
+    /// * `new_passphrase` - asked when the new file is created
+    fn save<F>(&mut self, new_passphrase: F) -> Result<()>
+    where
+        F: FnOnce() -> io::Result<PasswordValue>,
+    {
+        use std::io::Write;
+
+        let key = match self.key.take() {
+            Some(key) => key,
+            None => generate_key(&new_passphrase()?, &self.salt, &self.kdf_params)?,
+        };
+        let key = self.key.insert(key);
+
+        let mut content =
+            bincode::serialize(&self.secrets).map_err(|e| PasswordStorageError(e.to_string()))?;
+        let (tag, nonce) = encrypt_in_place(key, &self.salt, &mut content)?;
+        let file = SecretsFile {
+            file_signature: FILE_SIGNATURE,
+            salt: self.salt,
+            kdf_params: self.kdf_params,
+            nonce: nonce.into(),
+            tag: tag.into(),
+            content,
+        };
+
+        if let Some(root) = self.path.parent() {
+            fs::create_dir_all(root)?;
+        }
+
+        let temp_path = self.path.with_extension(TEMP_FILE_EXTENSION);
+        let mut options = fs::OpenOptions::new();
+        options.write(true).create(true).truncate(true);
+        #[cfg(unix)]
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, 0o600);
+
+        let mut temp_file = options.open(&temp_path)?;
+        temp_file.write_all(
+            &bincode::serialize(&file).map_err(|e| PasswordStorageError(e.to_string()))?,
+        )?;
+        temp_file.sync_all()?;
+        drop(temp_file);
+
+        fs::rename(&temp_path, &self.path)?;
+        Ok(())
+    }
+}
+
+impl From<io::Error> for PasswordStorageError {
+    fn from(value: io::Error) -> Self {
+        PasswordStorageError(value.to_string())
+    }
+}
+
+impl From<super::encryption::EncryptionError> for PasswordStorageError {
+    fn from(value: super::encryption::EncryptionError) -> Self {
+        PasswordStorageError(value.to_string())
+    }
+}
+
+/// Secure storage that keeps tokens and AES keys in the passphrase encrypted file in the config directory.
+/// It works on every platform, the passphrase is asked once per run.
+pub struct EncryptedFile {
+    path: PathBuf,
+    /// passphrase is asked once and kept until the storage is dropped
+    passphrase: Mutex<Option<PasswordValue>>,
+}
+
+impl Default for EncryptedFile {
+    fn default() -> Self {
+        EncryptedFile {
+            path: EncryptedFile::derive_file_path(),
+            passphrase: Mutex::new(None),
+        }
+    }
+}
+
+impl EncryptedFile {
+    /// Get the full path for the secrets file.
+    fn derive_file_path() -> PathBuf {
+        match dirs::config_dir() {
+            Some(conf) => {
+                let mut target = conf;
+                target.push(SECRETS_FILE_NAME);
+                target
+            }
+            None => PathBuf::from(SECRETS_FILE_NAME),
+        }
+    }
+
+    /// Open the secrets file, the passphrase is asked if it was not asked before.
+    fn open(&self) -> Result<SecretsStore> {
+        SecretsStore::open(&self.path, || self.passphrase(false))
+    }
+
+    /// Save the secrets, the passphrase of the new file is chosen if it was not asked before.
+    fn save(&self, store: &mut SecretsStore) -> Result<()> {
+        store.save(|| self.passphrase(true))
+    }
+
+    /// Get the cached passphrase or ask it.
+    ///
+    /// # Arguments
+    ///
+    /// * `new_file` - the passphrase is chosen and confirmed for the new file
+    fn passphrase(&self, new_file: bool) -> io::Result<PasswordValue> {
+        let mut cached = self
+            .passphrase
+            .lock()
+            .map_err(|e| io::Error::other(e.to_string()))?;
+        if let Some(passphrase) = cached.as_ref() {
+            return Ok(PasswordValue::from(passphrase.value.clone()));
+        }
+
+        let passphrase = if new_file {
+            new_password_prompt("Please choose the passphrase of the secrets file")?
+        } else {
+            password_prompt("Please enter the passphrase of the secrets file")?
+        };
+        *cached = Some(PasswordValue::from(passphrase.value.clone()));
+        Ok(passphrase)
+    }
+}
+
+impl SecureStorage for EncryptedFile {
+    fn update_password(&self, username: &str, bearer: &PasswordValue) -> Result<()> {
+        let mut store = self.open()?;
+        store
+            .secrets
+            .passwords
+            .insert(username.to_owned(), bearer.value.clone());
+        self.save(&mut store)?;
+        log::info!(
+            "New password was stored in [{}] / [{}]",
+            store.path.display(),
+            username
+        );
+        Ok(())
+    }
+
+    fn load_password(&self, username: &str) -> Result<Option<FastMailAccount>> {
+        let store = self.open()?;
+        Ok(store
+            .secrets
+            .passwords
+            .get(username)
+            .map(|value| FastMailAccount {
+                bearer: PasswordValue::from(value.clone()),
+            }))
+    }
+
+    fn load_key(&self, username: &str) -> Result<Option<AesKeyValue>> {
+        let store = self.open()?;
+        Ok(store
+            .secrets
+            .keys
+            .get(username)
+            .map(|value| AesKeyValue { value: *value }))
+    }
+
+    fn update_key(&self, username: &str, key: &AesKeyValue) -> Result<()> {
+        let mut store = self.open()?;
+        store.secrets.keys.insert(username.to_owned(), key.value);
+        self.save(&mut store)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::PathBuf;
+
+    use crate::secrets::PasswordValue;
+
+    use super::{SecretsStore, SECRETS_FILE_NAME};
+
+    fn passphrase(value: &str) -> impl FnOnce() -> std::io::Result<PasswordValue> + '_ {
+        move || Ok(PasswordValue::from(value.to_owned()))
+    }
+
+    fn not_asked() -> std::io::Result<PasswordValue> {
+        panic!("Passphrase is asked")
+    }
+
+    #[test]
+    fn save_and_reload_secrets() {
+        // make new tmp directory
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let mut path = PathBuf::from(tmp_dir.path());
+        path.push(SECRETS_FILE_NAME);
+
+        // the passphrase of the new file is chosen when it is saved
+        let mut store = SecretsStore::open(&path, not_asked).unwrap();
+        assert!(store.secrets.passwords.is_empty());
+        store
+            .secrets
+            .passwords
+            .insert("user@example.com".to_owned(), "token".to_owned());
+        store
+            .secrets
+            .keys
+            .insert("user@example.com".to_owned(), [7u8; 32]);
+        store
+            .save(passphrase("weak_passphrase"))
+            .expect("Secrets are not stored");
+        store.save(not_asked).expect("Secrets are not stored again");
+
+        // nothing is stored in plain text
+        let content = std::fs::read(&path).unwrap();
+        assert!(!content.windows(5).any(|w| w == b"token"));
+
+        let reloaded = SecretsStore::open(&path, passphrase("weak_passphrase")).unwrap();
+        assert_eq!(
+            reloaded
+                .secrets
+                .passwords
+                .get("user@example.com")
+                .map(String::as_str),
+            Some("token")
+        );
+        assert_eq!(
+            reloaded.secrets.keys.get("user@example.com"),
+            Some(&[7u8; 32])
+        );
+    }
+
+    #[test]
+    fn wrong_passphrase_is_rejected() {
+        // make new tmp directory
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let mut path = PathBuf::from(tmp_dir.path());
+        path.push(SECRETS_FILE_NAME);
+
+        let mut store = SecretsStore::open(&path, not_asked).unwrap();
+        store.save(passphrase("weak_passphrase")).unwrap();
+
+        assert!(SecretsStore::open(&path, passphrase("another_passphrase")).is_err());
+    }
+}
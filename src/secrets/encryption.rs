@@ -4,11 +4,12 @@ use aes_gcm::{
     aead::{consts::U16, AeadCore, KeyInit, Nonce},
     AeadInPlace, Aes256Gcm, Key, Tag,
 };
-use argon2::Argon2;
+use argon2::{Algorithm, Argon2, Params, Version};
 use rand_core::{OsRng, RngCore};
+use serde::{Deserialize, Serialize};
 use thiserror::Error;
 
-use super::{AesKeyValue, PasswordValue};
+use super::{AESKey, AesKeyValue, PasswordValue};
 
 pub const NONCE_SIZE_BYTES: usize = 96 / 8;
 
@@ -18,6 +19,9 @@ pub type EncryptionTag = Tag<U16>;
 
 pub type EncryptionNonce = Nonce<Aes256Gcm>;
 
+/// Associated data of every wrapped key.
+const WRAPPED_KEY_ASSOCIATED_DATA: &[u8] = b"masked-email-cli wrapped key";
+
 pub type Result<T> = std::result::Result<T, EncryptionError>;
 
 #[derive(Error, Debug)]
@@ -30,6 +34,71 @@ pub enum EncryptionError {
     Decryption(String),
 }
 
+/// Key derivation function supported by the database.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub enum KdfAlgorithm {
+    /// Argon2id v19
+    Argon2id,
+}
+
+/// Key derivation algorithm and its parameters, stored in the database header.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct KdfParams {
+    pub algorithm: KdfAlgorithm,
+    /// memory size in KiB
+    pub memory_cost: u32,
+    pub iterations: u32,
+    pub parallelism: u32,
+}
+
+impl Default for KdfParams {
+    /// Parameters of `Argon2::default()` that all databases before v2 use.
+    fn default() -> Self {
+        KdfParams {
+            algorithm: KdfAlgorithm::Argon2id,
+            memory_cost: Params::DEFAULT_M_COST,
+            iterations: Params::DEFAULT_T_COST,
+            parallelism: Params::DEFAULT_P_COST,
+        }
+    }
+}
+
+impl KdfParams {
+    /// Check the parameters are accepted by the algorithm and not weaker than the defaults. Existing
+    /// databases are opened with any parameters of their headers, only new keys are checked.
+    pub fn validate(&self) -> Result<()> {
+        let defaults = KdfParams::default();
+        if self.memory_cost < defaults.memory_cost
+            || self.iterations < defaults.iterations
+            || self.parallelism < defaults.parallelism
+        {
+            return Err(EncryptionError::KeyGeneration(format!(
+                "parameters are weaker than the defaults (memory cost {}, iterations {}, parallelism {})",
+                defaults.memory_cost, defaults.iterations, defaults.parallelism
+            )));
+        }
+        self.hasher().map(|_| ())
+    }
+
+    fn hasher(&self) -> Result<Argon2<'static>> {
+        match self.algorithm {
+            KdfAlgorithm::Argon2id => {
+                let params = Params::new(self.memory_cost, self.iterations, self.parallelism, None)
+                    .map_err(|e| EncryptionError::KeyGeneration(e.to_string()))?;
+                Ok(Argon2::new(Algorithm::Argon2id, Version::V0x13, params))
+            }
+        }
+    }
+}
+
+/// AES key encrypted with another key.
+#[derive(Clone, Debug, PartialEq, Eq, Deserialize, Serialize)]
+pub struct WrappedKey {
+    pub nonce: [u8; NONCE_SIZE_BYTES],
+    pub tag: [u8; 16],
+    pub key: AESKey,
+}
+
 /// Generate new salt for key derivations.
 pub fn generate_new_salt() -> KeyDerivationSalt {
     let mut key_derivation_salt = [0u8; NONCE_SIZE_BYTES];
@@ -38,15 +107,19 @@ pub fn generate_new_salt() -> KeyDerivationSalt {
 }
 
 /// Generate new AES key from the user email and password + salt.
+///
+/// # Arguments
+///
+/// * `kdf_params` - key derivation parameters, the database header keeps them
 pub fn generate_key(
     password: &PasswordValue,
     key_derivation_salt: &KeyDerivationSalt,
+    kdf_params: &KdfParams,
 ) -> Result<AesKeyValue> {
     // generate 256 bits key
     let mut output_key_material = AesKeyValue::default();
 
-    // Argon2 with default params (Argon2id v19)
-    let alg = Argon2::default();
+    let alg = kdf_params.hasher()?;
 
     // generate new AES key
     alg.hash_password_into(
@@ -59,6 +132,42 @@ pub fn generate_key(
     Ok(output_key_material)
 }
 
+/// Generate new random AES key.
+pub fn generate_random_key() -> AesKeyValue {
+    let mut key = AesKeyValue::default();
+    OsRng.fill_bytes(key.deref_mut());
+    key
+}
+
+/// Encrypt the key with another key.
+///
+/// # Arguments
+///
+/// * `wrapping_key` - key that encrypts, usually derived from the user passphrase
+/// * `key` - key to encrypt
+pub fn wrap_key(wrapping_key: &AesKeyValue, key: &AesKeyValue) -> Result<WrappedKey> {
+    let mut wrapped: AESKey = key.value;
+    let (tag, nonce) = encrypt_in_place(wrapping_key, WRAPPED_KEY_ASSOCIATED_DATA, &mut wrapped)?;
+    Ok(WrappedKey {
+        nonce: nonce.into(),
+        tag: tag.into(),
+        key: wrapped,
+    })
+}
+
+/// Decrypt the key encrypted by [wrap_key].
+pub fn unwrap_key(wrapping_key: &AesKeyValue, wrapped: &WrappedKey) -> Result<AesKeyValue> {
+    let mut key = AesKeyValue { value: wrapped.key };
+    decrypt_in_place(
+        wrapping_key,
+        &wrapped.nonce.into(),
+        WRAPPED_KEY_ASSOCIATED_DATA,
+        key.deref_mut(),
+        &wrapped.tag.into(),
+    )?;
+    Ok(key)
+}
+
 /// Encrypt the buffer (in-place).
 pub fn encrypt_in_place(
     key: &AesKeyValue,
@@ -98,7 +207,10 @@ pub fn decrypt_in_place(
 mod test {
     use crate::secrets::PasswordValue;
 
-    use super::{decrypt_in_place, encrypt_in_place, generate_key, generate_new_salt};
+    use super::{
+        decrypt_in_place, encrypt_in_place, generate_key, generate_new_salt, generate_random_key,
+        unwrap_key, wrap_key, KdfAlgorithm, KdfParams,
+    };
 
     #[test]
     fn decrypt_should_restore_the_content() {
@@ -106,6 +218,7 @@ mod test {
         let key = generate_key(
             &PasswordValue::from("MY_WEAK_PASSWORD".to_owned()),
             &generate_new_salt(),
+            &KdfParams::default(),
         )
         .expect("AES key generation failed");
 
@@ -129,4 +242,63 @@ mod test {
 
         assert_eq!(original_message, decrypted_message);
     }
+
+    #[test]
+    fn key_depends_on_kdf_params() {
+        let password = PasswordValue::from("MY_WEAK_PASSWORD".to_owned());
+        let salt = generate_new_salt();
+        let stronger = KdfParams {
+            algorithm: KdfAlgorithm::Argon2id,
+            memory_cost: 32 * 1024,
+            iterations: 3,
+            parallelism: 2,
+        };
+
+        let default_key = generate_key(&password, &salt, &KdfParams::default()).unwrap();
+        let stronger_key = generate_key(&password, &salt, &stronger).unwrap();
+        assert_ne!(default_key.value, stronger_key.value);
+
+        // the same params always derive the same key
+        let reloaded_key = generate_key(&password, &salt, &stronger).unwrap();
+        assert_eq!(stronger_key.value, reloaded_key.value);
+
+        let invalid = KdfParams {
+            iterations: 0,
+            ..KdfParams::default()
+        };
+        assert!(invalid.validate().is_err());
+        assert!(generate_key(&password, &salt, &invalid).is_err());
+
+        // weak parameters are not accepted for new keys
+        assert!(stronger.validate().is_ok());
+        assert!(KdfParams::default().validate().is_ok());
+        let weak = KdfParams {
+            memory_cost: 8,
+            iterations: 1,
+            parallelism: 1,
+            ..KdfParams::default()
+        };
+        assert!(weak.validate().is_err());
+    }
+
+    #[test]
+    fn unwrap_should_restore_the_key() {
+        let key = generate_random_key();
+        let wrapping_key = generate_key(
+            &PasswordValue::from("MY_PASSPHRASE".to_owned()),
+            &generate_new_salt(),
+            &KdfParams::default(),
+        )
+        .unwrap();
+
+        let wrapped = wrap_key(&wrapping_key, &key).expect("Key is not wrapped");
+        assert_ne!(wrapped.key, key.value);
+
+        let unwrapped = unwrap_key(&wrapping_key, &wrapped).expect("Key is not unwrapped");
+        assert_eq!(unwrapped.value, key.value);
+
+        // another passphrase cannot unwrap the key
+        let another_key = generate_random_key();
+        assert!(unwrap_key(&another_key, &wrapped).is_err());
+    }
 }
@@ -29,7 +29,7 @@ pub trait SecureStorage {
     /// # Returns
     ///
     /// nothing in case the operion finished successfully
-    fn update_password(username: &str, bearer: &PasswordValue) -> Result<()>;
+    fn update_password(&self, username: &str, bearer: &PasswordValue) -> Result<()>;
 
     /// Load password from the Apple KeyChain.
     ///
@@ -41,7 +41,7 @@ pub trait SecureStorage {
     ///
     /// empty in case of no user found. Otherwise it will be a sucessful result.
     ///
-    fn load_password(username: &str) -> Result<Option<FastMailAccount>>;
+    fn load_password(&self, username: &str) -> Result<Option<FastMailAccount>>;
 
     /// Load AES key from Keychain.
     ///
@@ -52,7 +52,7 @@ pub trait SecureStorage {
     /// # Returns
     ///
     /// nothing in case the operion finished successfully
-    fn load_key(username: &str) -> Result<Option<AesKeyValue>>;
+    fn load_key(&self, username: &str) -> Result<Option<AesKeyValue>>;
 
     /// Store AES key in KeyChain.
     ///
@@ -63,5 +63,5 @@ pub trait SecureStorage {
     /// # Returns
     ///
     /// nothing in case the operion finished successfully
-    fn update_key(username: &str, key: &AesKeyValue) -> Result<()>;
+    fn update_key(&self, username: &str, key: &AesKeyValue) -> Result<()>;
 }
@@ -87,16 +87,16 @@ impl KeyChain {
 }
 
 impl SecureStorage for KeyChain {
-    fn update_password(username: &str, bearer: &PasswordValue) -> Result<()> {
+    fn update_password(&self, username: &str, bearer: &PasswordValue) -> Result<()> {
         KeyChain::update_password(FASTMAIL_SERVICE_NAME, username, bearer)
     }
 
-    fn load_password(username: &str) -> Result<Option<FastMailAccount>> {
+    fn load_password(&self, username: &str) -> Result<Option<FastMailAccount>> {
         KeyChain::load_password(FASTMAIL_SERVICE_NAME, username)
             .map(|maybe_pass| maybe_pass.map(|p| FastMailAccount { bearer: p }))
     }
 
-    fn load_key(username: &str) -> Result<Option<super::AesKeyValue>> {
+    fn load_key(&self, username: &str) -> Result<Option<super::AesKeyValue>> {
         KeyChain::load_password(AES_SERVICE_NAME, username).map(|maybe_pass| {
             maybe_pass.and_then(|base64| {
                 #[allow(clippy::needless_borrows_for_generic_args)]
@@ -115,7 +115,7 @@ impl SecureStorage for KeyChain {
         })
     }
 
-    fn update_key(username: &str, aes: &super::AesKeyValue) -> Result<()> {
+    fn update_key(&self, username: &str, aes: &super::AesKeyValue) -> Result<()> {
         let encoded = general_purpose::STANDARD.encode(aes.value);
         let secure_string = PasswordValue { value: encoded };
         KeyChain::update_password(AES_SERVICE_NAME, username, &secure_string)
@@ -0,0 +1,96 @@
+use std::collections::HashMap;
+
+use crate::config::{AppConfig, SecretStorageKind};
+
+use super::{
+    credential_helper::CredentialHelper,
+    encrypted_file::EncryptedFile,
+    fastmail::{PasswordStorageError, Result, SecureStorage},
+};
+
+/// Makes the secure storage, the config is empty before `init`.
+pub type StorageFactory = fn(Option<&AppConfig>) -> Result<Box<dyn SecureStorage>>;
+
+/// Secure storage backends by name, the name comes from the config or the `--secret-storage` flag.
+pub struct StorageRegistry {
+    factories: HashMap<String, StorageFactory>,
+}
+
+impl Default for StorageRegistry {
+    /// Registry with all backends that are supported on this platform.
+    fn default() -> Self {
+        let mut registry = StorageRegistry {
+            factories: HashMap::new(),
+        };
+        #[cfg(target_os = "macos")]
+        registry.register(&SecretStorageKind::KeyChain.to_string(), |_| {
+            Ok(Box::new(super::keychain::KeyChain()))
+        });
+        registry.register(&SecretStorageKind::EncryptedFile.to_string(), |_| {
+            Ok(Box::new(EncryptedFile::default()))
+        });
+        registry.register(&SecretStorageKind::CredentialHelper.to_string(), |config| {
+            config
+                .and_then(|c| c.credential_helper.as_deref())
+                .map(|command| Box::new(CredentialHelper::new(command)) as Box<dyn SecureStorage>)
+                .ok_or_else(|| {
+                    PasswordStorageError(
+                        "please set the `credential_helper` command in the config".to_owned(),
+                    )
+                })
+        });
+        registry
+    }
+}
+
+impl StorageRegistry {
+    /// Add or replace the backend.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - backend name that is used in the config
+    /// * `factory` - function that makes the storage
+    pub fn register(&mut self, name: &str, factory: StorageFactory) {
+        self.factories.insert(name.to_owned(), factory);
+    }
+
+    /// Make the storage by the backend name.
+    ///
+    /// # Returns
+    ///
+    /// error if the backend is not registered or cannot be made with this config
+    pub fn create(&self, name: &str, config: Option<&AppConfig>) -> Result<Box<dyn SecureStorage>> {
+        match self.factories.get(name) {
+            Some(factory) => factory(config),
+            None => Err(PasswordStorageError(format!(
+                "secret storage `{name}` is not supported on this platform"
+            ))),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{secrets::PasswordValue, testing::memory::MemoryStorage};
+
+    use super::StorageRegistry;
+
+    #[test]
+    fn registered_backend_is_created() {
+        let mut registry = StorageRegistry::default();
+        registry.register("memory", |_| Ok(Box::new(MemoryStorage::default())));
+
+        let storage = registry
+            .create("memory", None)
+            .expect("Storage is not created");
+        storage
+            .update_password("user@example.com", &PasswordValue::from("token".to_owned()))
+            .unwrap();
+        let account = storage.load_password("user@example.com").unwrap().unwrap();
+        assert_eq!(account.bearer.value, "token");
+
+        assert!(registry.create("unknown", None).is_err());
+        // helper command is required
+        assert!(registry.create("helper", None).is_err());
+    }
+}
@@ -0,0 +1,130 @@
+use std::{
+    env, fs,
+    io::{self, Read},
+    path::Path,
+};
+
+use zeroize::Zeroize;
+
+use super::{
+    fastmail::{FastMailAccount, PasswordStorageError, Result, SecureStorage},
+    AesKeyValue, PasswordValue,
+};
+
+/// Path of the token file that means stdin.
+const STDIN_PATH: &str = "-";
+
+/// Read-only secure storage for headless runs (CI, cron). The fastmail token is provided by the environment
+/// variable, the file or the file descriptor, and the AES key is derived from it every time, nothing is persisted.
+pub struct TokenSource {
+    token: PasswordValue,
+}
+
+impl TokenSource {
+    /// Make the storage with the token that was read when the program started.
+    pub fn new(token: PasswordValue) -> Self {
+        TokenSource { token }
+    }
+
+    /// Read the token from the environment variable.
+    ///
+    /// # Returns
+    ///
+    /// empty if the variable is not set or blank
+    pub fn read_env(name: &str) -> Option<PasswordValue> {
+        env::var(name)
+            .ok()
+            .map(|value| value.trim().to_owned())
+            .filter(|value| !value.is_empty())
+            .map(PasswordValue::from)
+    }
+
+    /// Read the token from the file, `-` is stdin. Only the first line is used.
+    pub fn read_file(path: &Path) -> io::Result<PasswordValue> {
+        let mut content = String::new();
+        if path == Path::new(STDIN_PATH) {
+            io::stdin().read_to_string(&mut content)?;
+        } else {
+            content = fs::read_to_string(path)?;
+        }
+
+        let token = content.lines().next().unwrap_or_default().trim().to_owned();
+        content.zeroize();
+        if token.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("{} does not contain the token", path.display()),
+            ));
+        }
+        Ok(PasswordValue::from(token))
+    }
+
+    /// Read the token from the inherited file descriptor, for instance `--token-fd 3 3<<<"$TOKEN"`.
+    #[cfg(unix)]
+    pub fn read_fd(fd: u32) -> io::Result<PasswordValue> {
+        TokenSource::read_file(Path::new(&format!("/dev/fd/{fd}")))
+    }
+
+    #[cfg(not(unix))]
+    pub fn read_fd(_fd: u32) -> io::Result<PasswordValue> {
+        Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "file descriptors are supported on unix only",
+        ))
+    }
+}
+
+impl SecureStorage for TokenSource {
+    fn update_password(&self, _username: &str, _bearer: &PasswordValue) -> Result<()> {
+        Err(PasswordStorageError(
+            "token source is read-only, please update the token where it comes from".to_owned(),
+        ))
+    }
+
+    fn load_password(&self, _username: &str) -> Result<Option<FastMailAccount>> {
+        Ok(Some(FastMailAccount {
+            bearer: PasswordValue::from(self.token.value.clone()),
+        }))
+    }
+
+    fn load_key(&self, _username: &str) -> Result<Option<AesKeyValue>> {
+        // the key is derived from the token every time
+        Ok(None)
+    }
+
+    fn update_key(&self, username: &str, _key: &AesKeyValue) -> Result<()> {
+        log::debug!("AES key of [{username}] is not persisted by the token source");
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::fs;
+
+    use super::TokenSource;
+
+    #[test]
+    fn token_is_read_from_file() {
+        // make new tmp directory
+        let tmp_dir = tempfile::tempdir().unwrap();
+        let path = tmp_dir.path().join("token");
+        fs::write(&path, "  mock-token \nsecond line\n").unwrap();
+
+        let token = TokenSource::read_file(&path).expect("Token is not read");
+        assert_eq!(token.value, "mock-token");
+
+        fs::write(&path, "\n").unwrap();
+        assert!(TokenSource::read_file(&path).is_err());
+    }
+
+    #[test]
+    fn token_is_read_from_env() {
+        std::env::set_var("MASKED_EMAIL_CLI_TEST_TOKEN", "mock-token\n");
+        let token =
+            TokenSource::read_env("MASKED_EMAIL_CLI_TEST_TOKEN").expect("Token is not read");
+        assert_eq!(token.value, "mock-token");
+
+        assert!(TokenSource::read_env("MASKED_EMAIL_CLI_MISSING_TOKEN").is_none());
+    }
+}
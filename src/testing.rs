@@ -0,0 +1,4 @@
+//! Test support: in-process JMAP server and in-memory storages.
+
+pub mod memory;
+pub mod mock_jmap;
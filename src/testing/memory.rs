@@ -0,0 +1,99 @@
+use std::{
+    cell::{Cell, RefCell},
+    collections::HashMap,
+    io,
+};
+
+use config::ConfigError;
+
+use crate::{
+    config::{AppConfig, ConfigReader},
+    secrets::{
+        fastmail::{FastMailAccount, PasswordStorageError, Result, SecureStorage},
+        AESKey, AesKeyValue, PasswordValue,
+    },
+};
+
+/// Secure storage that keeps everything in memory, every test creates its own instance.
+#[derive(Default)]
+pub struct MemoryStorage {
+    passwords: RefCell<HashMap<String, String>>,
+    keys: RefCell<HashMap<String, AESKey>>,
+    /// key updates are rejected like a locked keychain does
+    locked_keys: Cell<bool>,
+}
+
+impl SecureStorage for MemoryStorage {
+    fn update_password(&self, username: &str, bearer: &PasswordValue) -> Result<()> {
+        self.passwords
+            .borrow_mut()
+            .insert(username.to_owned(), bearer.value.clone());
+        Ok(())
+    }
+
+    fn load_password(&self, username: &str) -> Result<Option<FastMailAccount>> {
+        Ok(self
+            .passwords
+            .borrow()
+            .get(username)
+            .map(|value| FastMailAccount {
+                bearer: PasswordValue {
+                    value: value.clone(),
+                },
+            }))
+    }
+
+    fn load_key(&self, username: &str) -> Result<Option<AesKeyValue>> {
+        Ok(self
+            .keys
+            .borrow()
+            .get(username)
+            .map(|value| AesKeyValue { value: *value }))
+    }
+
+    fn update_key(&self, username: &str, key: &AesKeyValue) -> Result<()> {
+        if self.locked_keys.get() {
+            return Err(PasswordStorageError("keys are locked".to_owned()));
+        }
+        self.keys
+            .borrow_mut()
+            .insert(username.to_owned(), key.value);
+        Ok(())
+    }
+}
+
+impl MemoryStorage {
+    /// Remove the AES key as if the database was copied to a new machine.
+    pub fn forget_key(&self, username: &str) {
+        self.keys.borrow_mut().remove(username);
+    }
+
+    /// Reject or accept the key updates.
+    pub fn lock_keys(&self, locked: bool) {
+        self.locked_keys.set(locked);
+    }
+}
+
+/// Configuration that is kept in memory.
+#[derive(Default)]
+pub struct MemoryConfig {
+    config: RefCell<Option<AppConfig>>,
+}
+
+impl ConfigReader for MemoryConfig {
+    fn try_load(&self) -> std::result::Result<AppConfig, ConfigError> {
+        self.config
+            .borrow()
+            .clone()
+            .ok_or_else(|| ConfigError::NotFound("memory config".to_owned()))
+    }
+
+    fn update(&self, config: &AppConfig) -> std::result::Result<(), io::Error> {
+        *self.config.borrow_mut() = Some(config.clone());
+        Ok(())
+    }
+
+    fn list_profiles(&self) -> std::result::Result<Vec<AppConfig>, ConfigError> {
+        Ok(self.config.borrow().iter().cloned().collect())
+    }
+}
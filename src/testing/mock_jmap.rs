@@ -0,0 +1,363 @@
+use std::{
+    collections::{BTreeMap, HashMap},
+    io::{BufRead, BufReader, Read, Write},
+    net::{SocketAddr, TcpListener, TcpStream},
+    sync::{Arc, Mutex},
+    thread,
+};
+
+use chrono::Utc;
+use serde_json::{json, Map, Value};
+
+const SESSION_PATH: &str = "/jmap/session";
+const API_PATH: &str = "/jmap/api";
+const ACCOUNT_ID: &str = "u-mock";
+
+#[derive(Clone, Copy, PartialEq)]
+enum Change {
+    Created,
+    Updated,
+    Destroyed,
+}
+
+/// In-memory alias store behind the mock server.
+struct MockStore {
+    token: String,
+    emails: BTreeMap<String, Value>,
+    /// current JMAP state, incremented on every change
+    state: u64,
+    /// changes are not calculated for states older than that
+    history_start: u64,
+    history: Vec<(u64, String, Change)>,
+    next_id: u64,
+    /// methods that always return the error type
+    failures: HashMap<String, String>,
+}
+
+/// Local HTTP server that implements the JMAP session and MaskedEmail get, set and changes methods.
+/// The server thread lives until the test process exits.
+pub struct MockJmapServer {
+    address: SocketAddr,
+    store: Arc<Mutex<MockStore>>,
+}
+
+impl MockJmapServer {
+    /// Start the server on a random local port.
+    ///
+    /// # Arguments
+    ///
+    /// * `token` - bearer token that the server accepts
+    pub fn start(token: &str) -> Self {
+        let listener = TcpListener::bind("127.0.0.1:0").expect("Mock server cannot be started");
+        let address = listener.local_addr().expect("Mock server has no address");
+        let store = Arc::new(Mutex::new(MockStore {
+            token: token.to_owned(),
+            emails: BTreeMap::new(),
+            state: 0,
+            history_start: 0,
+            history: Vec::new(),
+            next_id: 1,
+            failures: HashMap::new(),
+        }));
+
+        let server_store = Arc::clone(&store);
+        thread::spawn(move || {
+            for stream in listener.incoming().flatten() {
+                if let Err(e) = handle_connection(stream, address, &server_store) {
+                    log::warn!("Mock server connection failed: {e}");
+                }
+            }
+        });
+
+        MockJmapServer { address, store }
+    }
+
+    pub fn session_url(&self) -> String {
+        format!("http://{}{}", self.address, SESSION_PATH)
+    }
+
+    /// Add a new alias as if that was created in the web UI.
+    ///
+    /// # Returns
+    ///
+    /// fastmail ID of the new alias
+    pub fn add_email(&self, email: &str, description: &str) -> String {
+        let mut store = self.store.lock().unwrap();
+        let properties = json!({ "description": description, "state": "enabled" });
+        store.create(Some(email), properties.as_object().unwrap())
+    }
+
+    /// Remove the alias as if that was permanently deleted.
+    pub fn remove_email(&self, id: &str) {
+        let mut store = self.store.lock().unwrap();
+        if store.emails.remove(id).is_some() {
+            store.record(id, Change::Destroyed);
+        }
+    }
+
+    /// Current alias value in the JMAP format.
+    pub fn email(&self, id: &str) -> Option<Value> {
+        self.store.lock().unwrap().emails.get(id).cloned()
+    }
+
+    /// Accept another bearer token, the previous one is rejected after that.
+    pub fn set_token(&self, token: &str) {
+        self.store.lock().unwrap().token = token.to_owned();
+    }
+
+    /// Make the method fail with the JMAP error type on every call.
+    pub fn fail_method(&self, name: &str, error_type: &str) {
+        let mut store = self.store.lock().unwrap();
+        store
+            .failures
+            .insert(name.to_owned(), error_type.to_owned());
+    }
+
+    /// Drop the change log, all older states cannot be used to calculate changes anymore.
+    pub fn forget_history(&self) {
+        let mut store = self.store.lock().unwrap();
+        store.history.clear();
+        store.history_start = store.state;
+    }
+}
+
+impl MockStore {
+    fn record(&mut self, id: &str, change: Change) {
+        self.state += 1;
+        self.history.push((self.state, id.to_owned(), change));
+    }
+
+    fn create(&mut self, email: Option<&str>, properties: &Map<String, Value>) -> String {
+        let id = format!("masked-{}", self.next_id);
+        let email = match email {
+            Some(email) => email.to_owned(),
+            None => {
+                let prefix = properties
+                    .get("emailPrefix")
+                    .and_then(Value::as_str)
+                    .unwrap_or("alias");
+                format!("{}.{}@mock.example", prefix, self.next_id)
+            }
+        };
+        self.next_id += 1;
+
+        let record = json!({
+            "id": id,
+            "email": email,
+            "description": properties.get("description").cloned().unwrap_or(json!("")),
+            "forDomain": properties.get("forDomain").cloned().unwrap_or(json!("")),
+            "url": properties.get("url").cloned().unwrap_or(Value::Null),
+            "state": properties.get("state").cloned().unwrap_or(json!("pending")),
+            "createdAt": Utc::now().to_rfc3339(),
+            "lastMessageAt": Value::Null,
+        });
+        self.emails.insert(id.clone(), record);
+        self.record(&id, Change::Created);
+        id
+    }
+
+    fn get(&self, args: &Value) -> Value {
+        let list: Vec<Value> = match args["ids"].as_array() {
+            Some(ids) => ids
+                .iter()
+                .filter_map(|id| id.as_str().and_then(|id| self.emails.get(id)).cloned())
+                .collect(),
+            None => self.emails.values().cloned().collect(),
+        };
+
+        json!({
+            "accountId": ACCOUNT_ID,
+            "state": self.state.to_string(),
+            "list": list,
+            "notFound": [],
+        })
+    }
+
+    fn set(&mut self, args: &Value) -> Value {
+        let old_state = self.state.to_string();
+        let mut created = Map::new();
+        let mut updated = Map::new();
+        let mut not_updated = Map::new();
+
+        if let Some(create) = args["create"].as_object() {
+            for (create_id, properties) in create {
+                let properties = properties.as_object().cloned().unwrap_or_default();
+                let id = self.create(None, &properties);
+                let record = &self.emails[&id];
+                created.insert(
+                    create_id.clone(),
+                    json!({
+                        "id": id,
+                        "email": record["email"],
+                        "state": record["state"],
+                        "createdAt": record["createdAt"],
+                    }),
+                );
+            }
+        }
+
+        if let Some(update) = args["update"].as_object() {
+            for (id, patch) in update {
+                match (self.emails.get_mut(id), patch.as_object()) {
+                    (Some(record), Some(patch)) => {
+                        for (key, value) in patch {
+                            record[key] = value.clone();
+                        }
+                        self.record(id, Change::Updated);
+                        updated.insert(id.clone(), Value::Null);
+                    }
+                    _ => {
+                        not_updated.insert(id.clone(), json!({ "type": "notFound" }));
+                    }
+                }
+            }
+        }
+
+        json!({
+            "accountId": ACCOUNT_ID,
+            "oldState": old_state,
+            "newState": self.state.to_string(),
+            "created": created,
+            "updated": updated,
+            "notUpdated": not_updated,
+        })
+    }
+
+    fn changes(&self, args: &Value) -> Result<Value, Value> {
+        let since_state = args["sinceState"]
+            .as_str()
+            .and_then(|s| s.parse::<u64>().ok())
+            .filter(|s| *s >= self.history_start && *s <= self.state)
+            .ok_or_else(|| json!({ "type": "cannotCalculateChanges" }))?;
+
+        // the first and the last change of every ID
+        let mut changed: BTreeMap<&str, (Change, Change)> = BTreeMap::new();
+        for (_, id, change) in self.history.iter().filter(|(s, _, _)| *s > since_state) {
+            changed
+                .entry(id.as_str())
+                .and_modify(|(_, last)| *last = *change)
+                .or_insert((*change, *change));
+        }
+
+        let mut created = Vec::new();
+        let mut updated = Vec::new();
+        let mut destroyed = Vec::new();
+        for (id, (first, last)) in changed {
+            match (first, last) {
+                (Change::Created, Change::Destroyed) => (),
+                (Change::Created, _) => created.push(id),
+                (_, Change::Destroyed) => destroyed.push(id),
+                _ => updated.push(id),
+            }
+        }
+
+        Ok(json!({
+            "accountId": ACCOUNT_ID,
+            "oldState": since_state.to_string(),
+            "newState": self.state.to_string(),
+            "hasMoreChanges": false,
+            "created": created,
+            "updated": updated,
+            "destroyed": destroyed,
+        }))
+    }
+
+    fn invoke(&mut self, name: &str, args: &Value) -> (String, Value) {
+        if args["accountId"] != ACCOUNT_ID {
+            return ("error".to_owned(), json!({ "type": "accountNotFound" }));
+        }
+        if let Some(error_type) = self.failures.get(name) {
+            return ("error".to_owned(), json!({ "type": error_type }));
+        }
+
+        match name {
+            "MaskedEmail/get" => (name.to_owned(), self.get(args)),
+            "MaskedEmail/set" => (name.to_owned(), self.set(args)),
+            "MaskedEmail/changes" => match self.changes(args) {
+                Ok(resp) => (name.to_owned(), resp),
+                Err(error) => ("error".to_owned(), error),
+            },
+            _ => ("error".to_owned(), json!({ "type": "unknownMethod" })),
+        }
+    }
+}
+
+fn handle_connection(
+    stream: TcpStream,
+    address: SocketAddr,
+    store: &Mutex<MockStore>,
+) -> std::io::Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+
+    // request line and headers
+    let mut request_line = String::new();
+    reader.read_line(&mut request_line)?;
+    let mut content_length = 0usize;
+    let mut authorization = String::new();
+    loop {
+        let mut line = String::new();
+        reader.read_line(&mut line)?;
+        let line = line.trim_end();
+        if line.is_empty() {
+            break;
+        }
+        if let Some((name, value)) = line.split_once(':') {
+            match name.to_ascii_lowercase().as_str() {
+                "content-length" => content_length = value.trim().parse().unwrap_or_default(),
+                "authorization" => authorization = value.trim().to_owned(),
+                _ => (),
+            }
+        }
+    }
+
+    let mut body = vec![0u8; content_length];
+    reader.read_exact(&mut body)?;
+
+    let mut parts = request_line.split_whitespace();
+    let method = parts.next().unwrap_or_default();
+    let path = parts.next().unwrap_or_default();
+
+    let mut store = store.lock().unwrap();
+    let (status, response) = if authorization != format!("Bearer {}", store.token) {
+        ("401 Unauthorized", json!({ "error": "unauthorized" }))
+    } else {
+        match (method, path) {
+            ("GET", SESSION_PATH) => (
+                "200 OK",
+                json!({
+                    "primaryAccounts": { "urn:ietf:params:jmap:core": ACCOUNT_ID },
+                    "apiUrl": format!("http://{}{}", address, API_PATH),
+                }),
+            ),
+            ("POST", API_PATH) => match serde_json::from_slice::<Value>(&body) {
+                Ok(request) => {
+                    let calls = request["methodCalls"].as_array().cloned();
+                    let responses: Vec<Value> = calls
+                        .unwrap_or_default()
+                        .iter()
+                        .map(|call| {
+                            let (name, args) =
+                                store.invoke(call[0].as_str().unwrap_or(""), &call[1]);
+                            json!([name, args, call[2]])
+                        })
+                        .collect();
+                    ("200 OK", json!({ "methodResponses": responses }))
+                }
+                Err(_) => ("400 Bad Request", json!({ "error": "notJSON" })),
+            },
+            _ => ("404 Not Found", json!({ "error": "notFound" })),
+        }
+    };
+    drop(store);
+
+    let body = response.to_string();
+    let mut stream = stream;
+    write!(
+        stream,
+        "HTTP/1.1 {}\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}",
+        status,
+        body.len(),
+        body
+    )?;
+    stream.flush()
+}